@@ -0,0 +1,220 @@
+use mdbook_preprocessor::errors::{Error, Result};
+
+/// The attribute keys we understand
+pub static ATTRIBUTE_KEYS: &[&str] = &[
+    "name",
+    "id",
+    "engine",
+    "format",
+    "width",
+    "class",
+    "caption",
+    "alt",
+    "description",
+    "src",
+    "timeout",
+];
+
+/// The `key=value` attributes following our info string, e.g.
+/// ```` ```dot process name="Data flow" engine=neato format=png width=600 class=wide ````
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BlockAttributes {
+    /// The name of our graph, either from `name` or from any words that aren't attributes
+    pub name: Option<String>,
+    pub id: Option<String>,
+    pub engine: Option<String>,
+    pub formats: Option<Vec<String>>,
+    /// The width to display our diagram at, in pixels
+    pub width: Option<u32>,
+    /// Extra CSS classes to add to our diagram
+    pub class: Option<String>,
+    /// The caption to show under our diagram instead of its name, in inline Markdown
+    pub caption: Option<String>,
+    /// A short text alternative for readers who can't see our diagram
+    pub alt: Option<String>,
+    /// A longer description of what our diagram shows
+    pub description: Option<String>,
+    /// A file to render instead of our code, relative to our chapter
+    pub src: Option<String>,
+    /// How many seconds Graphviz gets to render our diagram, instead of the configured `timeout`
+    pub timeout: Option<u64>,
+}
+
+impl BlockAttributes {
+    /// Parse our attributes, returning them along with any keys we didn't recognize
+    pub fn parse(input: &str) -> Result<(BlockAttributes, Vec<String>)> {
+        let mut attributes = BlockAttributes::default();
+        let mut unknown_keys = Vec::new();
+        let mut name_words = Vec::new();
+
+        for token in tokenize(input)? {
+            match token {
+                Token::Word(word) => name_words.push(word),
+                Token::Attribute(key, value) => match key.as_str() {
+                    "name" => attributes.name = Some(value),
+                    "id" => attributes.id = Some(value),
+                    "engine" => attributes.engine = Some(value),
+                    "format" => {
+                        attributes.formats = Some(value.split(',').map(String::from).collect())
+                    }
+                    "width" => {
+                        attributes.width = Some(value.parse().map_err(|_| {
+                            Error::msg(format!(
+                                "The width attribute must be a number of pixels, got `{value}`"
+                            ))
+                        })?)
+                    }
+                    "class" => attributes.class = Some(value),
+                    "caption" => attributes.caption = Some(value),
+                    "alt" => attributes.alt = Some(value),
+                    "description" => attributes.description = Some(value),
+                    "src" => attributes.src = Some(value),
+                    "timeout" => {
+                        attributes.timeout = match value.parse() {
+                            Ok(seconds) if seconds > 0 => Some(seconds),
+                            _ => {
+                                return Err(Error::msg(format!(
+                                    "The timeout attribute must be a number of seconds, got `{value}`"
+                                )));
+                            }
+                        }
+                    }
+                    _ => unknown_keys.push(key),
+                },
+            }
+        }
+
+        // an explicit name wins over any loose words
+        if attributes.name.is_none() && !name_words.is_empty() {
+            attributes.name = Some(name_words.join(" "));
+        }
+
+        Ok((attributes, unknown_keys))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Attribute(String, String),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.next_if(|ch| ch.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(tokens);
+        }
+
+        let mut word = String::new();
+        while let Some(ch) = chars.next_if(|ch| !ch.is_whitespace() && *ch != '=') {
+            word.push(ch);
+        }
+
+        if chars.next_if_eq(&'=').is_none() || word.is_empty() {
+            // this is just part of our graph name, which can include a stray `=`
+            while let Some(ch) = chars.next_if(|ch| !ch.is_whitespace()) {
+                word.push(ch);
+            }
+            tokens.push(Token::Word(word));
+            continue;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(ch) => value.push(ch),
+                        None => break,
+                    },
+                    Some(ch) => value.push(ch),
+                    None => {
+                        return Err(Error::msg(format!(
+                            "Unterminated quote in the `{word}` attribute"
+                        )));
+                    }
+                }
+            }
+        } else {
+            while let Some(ch) = chars.next_if(|ch| !ch.is_whitespace()) {
+                value.push(ch);
+            }
+        }
+        tokens.push(Token::Attribute(word.to_lowercase(), value));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            BlockAttributes::parse("   ").unwrap(),
+            (BlockAttributes::default(), vec![])
+        );
+    }
+
+    #[test]
+    fn legacy_name() {
+        let (attributes, _) = BlockAttributes::parse(" Graph Name").unwrap();
+
+        assert_eq!(attributes.name, Some(String::from("Graph Name")));
+    }
+
+    #[test]
+    fn all_attributes() {
+        let (attributes, unknown_keys) = BlockAttributes::parse(
+            r#"name="Data \"flow\"" id=flow engine=neato format=png,pdf width=600 class=wide caption="The *data* flow" alt="Requests flow into storage" description="Every request is written to storage" src=diagrams/flow.dot timeout=30"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            attributes,
+            BlockAttributes {
+                name: Some(String::from("Data \"flow\"")),
+                id: Some(String::from("flow")),
+                engine: Some(String::from("neato")),
+                formats: Some(vec![String::from("png"), String::from("pdf")]),
+                width: Some(600),
+                class: Some(String::from("wide")),
+                caption: Some(String::from("The *data* flow")),
+                alt: Some(String::from("Requests flow into storage")),
+                description: Some(String::from("Every request is written to storage")),
+                src: Some(String::from("diagrams/flow.dot")),
+                timeout: Some(30),
+            }
+        );
+        assert!(unknown_keys.is_empty());
+    }
+
+    #[test]
+    fn mixed_name_and_attributes() {
+        let (attributes, _) = BlockAttributes::parse("Graph engine=circo Name").unwrap();
+
+        assert_eq!(attributes.name, Some(String::from("Graph Name")));
+        assert_eq!(attributes.engine, Some(String::from("circo")));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let (attributes, unknown_keys) = BlockAttributes::parse("Name colour=red").unwrap();
+
+        assert_eq!(attributes.name, Some(String::from("Name")));
+        assert_eq!(unknown_keys, vec![String::from("colour")]);
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(BlockAttributes::parse("width=wide").is_err());
+        assert!(BlockAttributes::parse("timeout=0").is_err());
+        assert!(BlockAttributes::parse(r#"name="Unterminated"#).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mdbook_preprocessor::errors::Result;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+use crate::renderer::couldnt_run;
+
+/// An on-disk cache of Graphviz output, keyed by everything that can change what Graphviz renders
+pub struct RenderCache {
+    dir: PathBuf,
+}
+
+impl RenderCache {
+    pub fn new(dir: PathBuf) -> RenderCache {
+        RenderCache { dir }
+    }
+
+    pub fn key(
+        version: &str,
+        engine: &str,
+        arguments: &[String],
+        env: &BTreeMap<String, String>,
+        code: &str,
+    ) -> String {
+        let mut hasher = Sha256::new();
+        for part in [version, engine]
+            .into_iter()
+            .chain(arguments.iter().map(String::as_str))
+            // environment variables like `GDFONTPATH` change what Graphviz draws too
+            .chain(
+                env.iter()
+                    .flat_map(|(key, value)| [key.as_str(), value.as_str()]),
+            )
+            .chain([code])
+        {
+            hasher.update(part.as_bytes());
+            // separate each part so that moving bytes between parts changes the key
+            hasher.update([0]);
+        }
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.entry_path(key)).await.ok()
+    }
+
+    pub async fn put(&self, key: &str, output: &[u8]) -> Result<()> {
+        static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+        fs::create_dir_all(&self.dir).await?;
+        // write our entry under a name nothing else uses then move it into place, so that an
+        // interrupted write or another build writing the same entry never leaves a partial entry
+        let temp_path = self.dir.join(format!(
+            "{key}.{}.{}.tmp",
+            process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp_path, output).await?;
+        if let Err(e) = fs::rename(&temp_path, self.entry_path(key)).await {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+/// The version string reported by a Graphviz executable's `-V`, this is only looked up once per
+/// executable each run
+pub async fn graphviz_version(command: &Path, env: &BTreeMap<String, String>) -> Result<String> {
+    static GRAPHVIZ_VERSIONS: Mutex<BTreeMap<PathBuf, Arc<OnceCell<String>>>> =
+        Mutex::new(BTreeMap::new());
+
+    let version = GRAPHVIZ_VERSIONS
+        .lock()
+        .unwrap()
+        .entry(command.to_path_buf())
+        .or_default()
+        .clone();
+    version
+        .get_or_try_init(|| async {
+            let output = Command::new(command)
+                .arg("-V")
+                .envs(env)
+                .stdin(Stdio::null())
+                .output()
+                .await
+                .map_err(|e| couldnt_run(command, e))?;
+            if output.status.success() {
+                // Graphviz reports its version on stderr
+                Ok(String::from_utf8_lossy(&output.stderr).trim().to_string())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Couldn't determine the Graphviz version",
+                )
+                .into())
+            }
+        })
+        .await
+        .cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn arguments() -> Vec<String> {
+        vec![String::from("-Tsvg")]
+    }
+
+    fn no_env() -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+
+    #[test]
+    fn key_is_stable() {
+        assert_eq!(
+            RenderCache::key("1.0", "dot", &arguments(), &no_env(), "digraph { a -> b }"),
+            RenderCache::key("1.0", "dot", &arguments(), &no_env(), "digraph { a -> b }")
+        );
+    }
+
+    #[test]
+    fn key_changes_with_inputs() {
+        let key = RenderCache::key("1.0", "dot", &arguments(), &no_env(), "digraph { a -> b }");
+
+        assert_ne!(
+            key,
+            RenderCache::key("2.0", "dot", &arguments(), &no_env(), "digraph { a -> b }")
+        );
+        assert_ne!(
+            key,
+            RenderCache::key(
+                "1.0",
+                "neato",
+                &arguments(),
+                &no_env(),
+                "digraph { a -> b }"
+            )
+        );
+        assert_ne!(
+            key,
+            RenderCache::key("1.0", "dot", &[], &no_env(), "digraph { a -> b }")
+        );
+        assert_ne!(
+            key,
+            RenderCache::key("1.0", "dot", &arguments(), &no_env(), "digraph { b -> a }")
+        );
+
+        let env = BTreeMap::from([(String::from("GDFONTPATH"), String::from("fonts"))]);
+        assert_ne!(
+            key,
+            RenderCache::key("1.0", "dot", &arguments(), &env, "digraph { a -> b }")
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_graphviz_version() {
+        let error = graphviz_version(Path::new("test-output/missing-graphviz/dot"), &no_env())
+            .await
+            .unwrap_err();
+
+        assert!(
+            format!("{error:#}").starts_with("Couldn't run `test-output/missing-graphviz/dot`"),
+            "{error:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let cache = RenderCache::new(PathBuf::from("test-output/cache"));
+        let key = RenderCache::key(
+            "1.0",
+            "dot",
+            &arguments(),
+            &no_env(),
+            "digraph { round -> trip }",
+        );
+
+        cache.put(&key, b"<svg></svg>").await.unwrap();
+
+        assert_eq!(cache.get(&key).await, Some(b"<svg></svg>".to_vec()));
+        assert_eq!(cache.get("missing").await, None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_puts() {
+        let dir = PathBuf::from("test-output/cache-concurrent");
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = RenderCache::new(dir.clone());
+        let key = RenderCache::key("1.0", "dot", &arguments(), &no_env(), "digraph { a -> b }");
+
+        let output = vec![b'x'; 100_000];
+        futures::future::join_all((0..8).map(|_| cache.put(&key, &output)))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(cache.get(&key).await, Some(output));
+        // only our entry is left behind, none of the files we wrote it to first
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
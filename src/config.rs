@@ -0,0 +1,521 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::{Component, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use mdbook_preprocessor::errors::{Error, Result};
+use serde::{Deserialize, Deserializer};
+use toml::{Table, Value};
+
+use crate::preprocessor::PREPROCESSOR_NAME;
+
+pub static DEFAULT_INFO_STRING_PREFIX: &str = "dot process";
+pub static DEFAULT_ENGINE: &str = "dot";
+pub static SVG_FORMAT: &str = "svg";
+/// The layout engines that ship with Graphviz
+pub static GRAPHVIZ_ENGINES: &[&str] = &[
+    "dot",
+    "neato",
+    "fdp",
+    "sfdp",
+    "circo",
+    "twopi",
+    "osage",
+    "patchwork",
+];
+/// Keys in our table that mdbook uses to run us rather than being for us
+static MDBOOK_KEYS: &[&str] = &["command", "before", "after", "renderers", "optional"];
+
+/// Our `[preprocessor.graphviz]` configuration
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+#[non_exhaustive]
+pub struct GraphvizConfig {
+    pub output_to_file: bool,
+    pub link_to_file: bool,
+    pub info_string: String,
+    pub arguments: Vec<String>,
+    pub engine: String,
+    /// The output formats to render in file mode, the first is the one displayed on the page
+    #[serde(rename = "format", deserialize_with = "one_or_many")]
+    pub formats: Vec<String>,
+    /// Where to cache rendered output between builds, caching is disabled if this isn't set
+    pub cache_dir: Option<PathBuf>,
+    pub on_error: OnError,
+    /// A directory in the book source to write generated files to instead of next to each chapter
+    pub output_dir: Option<PathBuf>,
+    /// Remove files we generated on a previous run that we no longer generate
+    pub clean_stale_files: bool,
+    /// Only list the stale files we would remove
+    pub clean_dry_run: bool,
+    pub file_names: FileNames,
+    /// Draw inline diagrams with CSS custom properties instead of black and white
+    pub theme_aware: bool,
+    /// Caption each diagram with its figure number, like `Figure 3.2: Graph Name`
+    pub figure_numbers: bool,
+    /// Wrap each diagram in a `<figure>` captioned with its name
+    pub figures: bool,
+    /// Warn about diagrams without a text alternative, once per chapter
+    pub warn_missing_alt: bool,
+    /// Render the `.dot` and `.gv` files in our book source to images next to them
+    pub render_source_files: bool,
+    pub backend: Backend,
+    /// Whether our diagrams are rendered while building or in the reader's browser
+    pub render: RenderMode,
+    /// Where the browser loads viz.js from when rendering on the client. We don't bundle viz.js and
+    /// there's no default so that books don't load scripts from a CDN unless they ask to
+    pub viz_url: Option<String>,
+    /// The most Graphviz processes to run at once, defaults to the number of CPUs
+    pub max_concurrency: Option<usize>,
+    /// How many seconds Graphviz gets to render each diagram before it's killed
+    pub timeout: Option<u64>,
+    /// The largest diagram source we'll render, in bytes
+    pub max_input_size: Option<usize>,
+    /// The largest output we'll accept from Graphviz, in bytes
+    pub max_output_size: Option<usize>,
+    /// The directory holding the Graphviz executables, instead of finding them on the `PATH`
+    pub graphviz_path: Option<PathBuf>,
+    /// The executable to run for a layout engine, instead of the one in `graphviz_path`
+    pub engine_paths: BTreeMap<String, PathBuf>,
+    /// Extra environment variables for Graphviz, like `GDFONTPATH`
+    pub env: BTreeMap<String, String>,
+}
+
+impl Default for GraphvizConfig {
+    fn default() -> Self {
+        Self {
+            output_to_file: false,
+            link_to_file: false,
+            info_string: DEFAULT_INFO_STRING_PREFIX.to_string(),
+            arguments: vec![],
+            engine: DEFAULT_ENGINE.to_string(),
+            formats: vec![SVG_FORMAT.to_string()],
+            cache_dir: None,
+            on_error: OnError::Fail,
+            output_dir: None,
+            clean_stale_files: true,
+            clean_dry_run: false,
+            file_names: FileNames::Index,
+            theme_aware: false,
+            figure_numbers: false,
+            figures: false,
+            warn_missing_alt: true,
+            render_source_files: false,
+            backend: Backend::Cli,
+            render: RenderMode::Build,
+            viz_url: None,
+            max_concurrency: None,
+            timeout: None,
+            max_input_size: None,
+            max_output_size: None,
+            graphviz_path: None,
+            engine_paths: BTreeMap::new(),
+            env: BTreeMap::new(),
+        }
+    }
+}
+
+impl FromStr for GraphvizConfig {
+    type Err = Error;
+
+    /// Parse and validate the TOML contents of a `[preprocessor.graphviz]` table
+    fn from_str(config: &str) -> Result<GraphvizConfig> {
+        let table = config.parse::<Table>().map_err(|e| {
+            Error::new(e).context(format!(
+                "Invalid [preprocessor.{PREPROCESSOR_NAME}] configuration"
+            ))
+        })?;
+
+        GraphvizConfig::from_table(table)
+    }
+}
+
+impl GraphvizConfig {
+    /// Parse and validate our `[preprocessor.graphviz]` table
+    pub(crate) fn from_table(mut table: Table) -> Result<GraphvizConfig> {
+        for key in MDBOOK_KEYS {
+            table.remove(*key);
+        }
+        let has_format = table.contains_key("format");
+
+        let mut config: GraphvizConfig = Value::Table(table).try_into().map_err(|e| {
+            Error::new(e).context(format!(
+                "Invalid [preprocessor.{PREPROCESSOR_NAME}] configuration"
+            ))
+        })?;
+
+        // the output format used to be set via a `-T` argument, so keep respecting that
+        if let Some(format) = take_format_arguments(&mut config.arguments)
+            && !has_format
+        {
+            config.formats = vec![format];
+        }
+
+        config.validate().map_err(|e| {
+            e.context(format!(
+                "Invalid [preprocessor.{PREPROCESSOR_NAME}] configuration"
+            ))
+        })?;
+
+        Ok(config)
+    }
+
+    /// The most Graphviz processes to run at once
+    pub fn concurrency_limit(&self) -> usize {
+        self.max_concurrency.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(NonZeroUsize::get)
+                .unwrap_or(1)
+        })
+    }
+
+    /// How long Graphviz gets to render each diagram
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    /// The Graphviz executable to run for `engine`
+    pub fn engine_command(&self, engine: &str) -> PathBuf {
+        match (self.engine_paths.get(engine), &self.graphviz_path) {
+            (Some(path), _) => path.clone(),
+            (None, Some(graphviz_path)) => graphviz_path.join(engine),
+            (None, None) => PathBuf::from(engine),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        validate_engine(&self.engine)?;
+        for engine in self.engine_paths.keys() {
+            validate_engine(engine).map_err(|e| e.context("Invalid engine in engine-paths"))?;
+        }
+        validate_formats(&self.formats)?;
+        self.backend.validate(&self.engine, &self.formats)?;
+
+        if self.max_concurrency == Some(0) {
+            return Err(Error::msg("max-concurrency must be at least 1"));
+        }
+        if self.timeout == Some(0) {
+            return Err(Error::msg("timeout must be at least 1 second"));
+        }
+
+        // the browser can only render inline diagrams
+        if self.render == RenderMode::Client && (self.output_to_file || self.render_source_files) {
+            return Err(Error::msg(
+                "render = \"client\" can't be combined with output-to-file or render-source-files",
+            ));
+        }
+        if self.render == RenderMode::Client && self.viz_url.is_none() {
+            return Err(Error::msg(
+                "render = \"client\" needs viz-url to say where to load viz.js from, it isn't bundled \
+                 with mdbook-graphviz, e.g. a copy of viz-standalone.js in your book",
+            ));
+        }
+
+        // our files need to end up in the book source so that renderers copy them over
+        if let Some(output_dir) = &self.output_dir
+            && !output_dir
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Error::msg(format!(
+                "output-dir `{}` must be a relative path inside the book source directory",
+                output_dir.display()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// What to do when a block fails to render
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Fail the whole build
+    Fail,
+    /// Print a warning and leave the original code block in place
+    Warn,
+    /// Print a warning and replace the block with an error panel
+    Inline,
+}
+
+/// When our diagrams are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    /// While building the book
+    Build,
+    /// In the reader's browser with viz.js, leaving the DOT source in the page
+    Client,
+}
+
+/// What renders our diagrams
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The Graphviz command line tools
+    Cli,
+    /// The pure Rust layout-rs crate, which needs our `native` feature
+    Native,
+}
+
+impl Backend {
+    /// Check that we can render this engine and these formats
+    pub fn validate(self, engine: &str, formats: &[String]) -> Result<()> {
+        match self {
+            Backend::Cli => Ok(()),
+            Backend::Native if !cfg!(feature = "native") => Err(Error::msg(
+                "The `native` backend needs mdbook-graphviz to be built with the `native` feature, \
+                 e.g. `cargo install mdbook-graphviz --features native`",
+            )),
+            Backend::Native if engine != DEFAULT_ENGINE => Err(Error::msg(format!(
+                "The `native` backend only supports the `{DEFAULT_ENGINE}` layout engine, not `{engine}`"
+            ))),
+            Backend::Native => match formats.iter().find(|format| *format != SVG_FORMAT) {
+                Some(format) => Err(Error::msg(format!(
+                    "The `native` backend can only render `{SVG_FORMAT}`, not `{format}`"
+                ))),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+/// How we name the files generated for blocks without an `id` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileNames {
+    /// Use the position of the block in its chapter
+    Index,
+    /// Use a hash of the block's code, so names don't change when other blocks are added
+    Hash,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged, expecting = "a string or an array of strings")]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Remove any `-T<format>` arguments, returning the last format they selected
+fn take_format_arguments(arguments: &mut Vec<String>) -> Option<String> {
+    let mut format = None;
+    arguments.retain(|argument| match argument.strip_prefix("-T") {
+        Some(value) => {
+            format = Some(value.to_string());
+            false
+        }
+        None => true,
+    });
+
+    format
+}
+
+pub fn validate_formats(formats: &[String]) -> Result<()> {
+    if formats.is_empty() || formats.iter().any(|format| format.is_empty()) {
+        Err(Error::msg(
+            "The format option requires at least one non-empty format",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The file extension for a Graphviz output format, dropping any renderer like in `png:cairo`
+pub fn format_extension(format: &str) -> &str {
+    format.split(':').next().unwrap_or(format)
+}
+
+pub fn validate_engine(engine: &str) -> Result<()> {
+    if GRAPHVIZ_ENGINES.contains(&engine) {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "Unknown Graphviz layout engine `{engine}`, expected one of: {}",
+            GRAPHVIZ_ENGINES.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(config: &str) -> Result<GraphvizConfig> {
+        config.parse()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse("").unwrap();
+
+        assert!(!config.output_to_file);
+        assert_eq!(config.info_string, DEFAULT_INFO_STRING_PREFIX);
+        assert_eq!(config.formats, vec![SVG_FORMAT.to_string()]);
+        assert!(config.concurrency_limit() >= 1);
+        assert_eq!(config.engine_command("dot"), PathBuf::from("dot"));
+    }
+
+    #[test]
+    fn all_options() {
+        let config = parse(
+            r#"
+command = "mdbook-graphviz"
+after = ["links"]
+output-to-file = true
+link-to-file = true
+info-string = "graphviz"
+arguments = ["-Gdpi=300"]
+engine = "neato"
+format = ["png", "pdf"]
+cache-dir = ".graphviz-cache"
+on-error = "inline"
+output-dir = "graphviz-out"
+clean-stale-files = false
+clean-dry-run = true
+file-names = "hash"
+theme-aware = true
+figure-numbers = true
+figures = true
+warn-missing-alt = false
+render-source-files = true
+backend = "cli"
+render = "build"
+viz-url = "/js/viz-standalone.js"
+max-concurrency = 4
+timeout = 30
+max-input-size = 100000
+max-output-size = 5000000
+graphviz-path = "/opt/graphviz/bin"
+engine-paths = { neato = "/usr/local/bin/neato" }
+env = { GDFONTPATH = "fonts" }
+"#,
+        )
+        .unwrap();
+
+        assert!(config.output_to_file);
+        assert!(config.link_to_file);
+        assert_eq!(config.info_string, "graphviz");
+        assert_eq!(config.arguments, vec![String::from("-Gdpi=300")]);
+        assert_eq!(config.engine, "neato");
+        assert_eq!(
+            config.formats,
+            vec![String::from("png"), String::from("pdf")]
+        );
+        assert_eq!(config.cache_dir, Some(PathBuf::from(".graphviz-cache")));
+        assert_eq!(config.on_error, OnError::Inline);
+        assert_eq!(config.output_dir, Some(PathBuf::from("graphviz-out")));
+        assert!(!config.clean_stale_files);
+        assert!(config.clean_dry_run);
+        assert_eq!(config.file_names, FileNames::Hash);
+        assert!(config.theme_aware);
+        assert!(config.figure_numbers);
+        assert!(config.figures);
+        assert!(!config.warn_missing_alt);
+        assert!(config.render_source_files);
+        assert_eq!(config.backend, Backend::Cli);
+        assert_eq!(config.render, RenderMode::Build);
+        assert_eq!(config.viz_url.as_deref(), Some("/js/viz-standalone.js"));
+        assert_eq!(config.concurrency_limit(), 4);
+        assert_eq!(config.timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(config.max_input_size, Some(100000));
+        assert_eq!(config.max_output_size, Some(5000000));
+        assert_eq!(
+            config.engine_command("dot"),
+            PathBuf::from("/opt/graphviz/bin/dot")
+        );
+        assert_eq!(
+            config.engine_command("neato"),
+            PathBuf::from("/usr/local/bin/neato")
+        );
+        assert_eq!(config.env["GDFONTPATH"], "fonts");
+    }
+
+    #[test]
+    fn single_format() {
+        let config = parse(r#"format = "png""#).unwrap();
+
+        assert_eq!(config.formats, vec![String::from("png")]);
+    }
+
+    #[test]
+    fn format_from_arguments() {
+        let config = parse(r#"arguments = ["-Tsvg", "-Gdpi=300", "-Tpng:cairo"]"#).unwrap();
+
+        assert_eq!(config.formats, vec![String::from("png:cairo")]);
+        assert_eq!(config.arguments, vec![String::from("-Gdpi=300")]);
+        assert_eq!(format_extension("png:cairo"), "png");
+
+        let config = parse(
+            r#"
+arguments = ["-Tpng"]
+format = "pdf"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.formats, vec![String::from("pdf")]);
+        assert!(config.arguments.is_empty());
+    }
+
+    #[test]
+    fn wrong_type() {
+        let error = parse(r#"output-to-file = "yes""#).unwrap_err();
+
+        let message = format!("{error:#}");
+        assert!(message.contains("output-to-file"), "{message}");
+        assert!(message.contains("expected a boolean"), "{message}");
+    }
+
+    #[test]
+    fn unknown_key() {
+        let error = parse("output_to_file = true").unwrap_err();
+
+        let message = format!("{error:#}");
+        assert!(
+            message.contains("unknown field `output_to_file`"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(parse(r#"engine = "nonsense""#).is_err());
+        assert!(parse(r#"on-error = "ignore""#).is_err());
+        assert!(parse("format = []").is_err());
+        assert!(parse(r#"output-dir = "../outside""#).is_err());
+        assert!(parse(r#"backend = "wasm""#).is_err());
+        assert!(parse("max-concurrency = 0").is_err());
+        assert!(parse("timeout = 0").is_err());
+        assert!(parse(r#"engine-paths = { nonsense = "/bin/nonsense" }"#).is_err());
+        assert!(parse("render = \"client\"\nviz-url = \"viz.js\"\noutput-to-file = true").is_err());
+        // we don't pick a CDN for the book
+        assert!(parse(r#"render = "client""#).is_err());
+        assert!(parse("render = \"client\"\nviz-url = \"viz.js\"").is_ok());
+    }
+
+    #[test]
+    fn native_backend() {
+        if cfg!(feature = "native") {
+            assert!(parse(r#"backend = "native""#).is_ok());
+            assert!(parse("backend = \"native\"\nengine = \"neato\"").is_err());
+            assert!(parse("backend = \"native\"\nformat = \"png\"").is_err());
+        } else {
+            let error = parse(r#"backend = "native""#).unwrap_err();
+            assert!(
+                format!("{error:#}").contains("--features native"),
+                "{error:#}"
+            );
+        }
+    }
+}
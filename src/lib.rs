@@ -0,0 +1,67 @@
+//! An [mdBook](https://github.com/rust-lang/mdBook) preprocessor that renders Graphviz code blocks
+//! to SVGs or image files.
+//!
+//! [`GraphvizPreprocessor`] is what the `mdbook-graphviz` binary runs, configured from the
+//! `[preprocessor.graphviz]` table of the book. To render with something other than the Graphviz
+//! CLI, implement [`GraphvizRenderer`] and drive it with [`Graphviz`]:
+//!
+//! ```
+//! use mdbook_graphviz::{Graphviz, GraphvizBlock, GraphvizConfig, GraphvizRenderer};
+//! use mdbook_markdown::pulldown_cmark::Event;
+//! use mdbook_preprocessor::book::Book;
+//! use mdbook_preprocessor::errors::Result;
+//!
+//! /// Replaces each block with its code as plain text, after a prefix
+//! struct TextRenderer {
+//!     prefix: String,
+//! }
+//!
+//! impl GraphvizRenderer for TextRenderer {
+//!     async fn render_graphviz<'a>(
+//!         &self,
+//!         block: GraphvizBlock,
+//!         _config: &GraphvizConfig,
+//!     ) -> Result<Vec<Event<'a>>> {
+//!         Ok(vec![Event::Text(format!("{}{}", self.prefix, block.code).into())])
+//!     }
+//! }
+//!
+//! async fn render(mut book: Book) -> Result<Book> {
+//!     let config = "output-to-file = false".parse::<GraphvizConfig>()?;
+//!     let renderer = TextRenderer {
+//!         prefix: String::from("Graph: "),
+//!     };
+//!
+//!     Graphviz::new(renderer, "src".into(), config)
+//!         .with_book(&book)
+//!         .process_book(&mut book)
+//!         .await?;
+//!
+//!     Ok(book)
+//! }
+//! ```
+
+#[macro_use]
+extern crate lazy_static;
+
+mod attributes;
+mod cache;
+mod config;
+mod manifest;
+mod native;
+mod preprocessor;
+mod renderer;
+mod source_files;
+mod svg;
+
+pub use crate::attributes::BlockAttributes;
+pub use crate::config::{Backend, FileNames, GraphvizConfig, OnError, RenderMode};
+pub use crate::preprocessor::{Graphviz, GraphvizBlock, GraphvizPreprocessor, PREPROCESSOR_NAME};
+pub use crate::renderer::{
+    CLIGraphviz, CLIGraphvizToFile, ClientGraphviz, GraphvizError, GraphvizRenderer,
+};
+
+/// The stylesheet that colors `theme-aware` diagrams for each mdBook theme
+pub static THEME_CSS: &str = include_str!("mdbook-graphviz.css");
+/// The script that renders diagrams in the browser for `render = "client"`
+pub static CLIENT_JS: &str = include_str!("mdbook-graphviz.js");
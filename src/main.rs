@@ -1,17 +1,11 @@
-#[macro_use]
-extern crate lazy_static;
-
 use std::io;
 use std::process;
 
-use crate::preprocessor::GraphvizPreprocessor;
 use clap::{Parser, Subcommand};
+use mdbook_graphviz::{CLIENT_JS, GraphvizPreprocessor, THEME_CSS};
 use mdbook_preprocessor::Preprocessor;
 use mdbook_preprocessor::errors::Error;
 
-mod preprocessor;
-mod renderer;
-
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -23,6 +17,10 @@ struct Cli {
 enum Commands {
     /// Check whether a renderer is supported by this preprocessor
     Supports { renderer: String },
+    /// Print the stylesheet that colors `theme-aware` diagrams for each mdBook theme
+    Css,
+    /// Print the script that renders diagrams in the browser for `render = "client"`
+    Js,
 }
 
 fn main() {
@@ -33,7 +31,9 @@ fn main() {
     match cli.command {
         None => {
             if let Err(e) = handle_preprocessing(&preprocessor) {
-                eprintln!("{e}");
+                // include the full chain of causes so we know which block failed and why, but
+                // not the backtrace that `{:?}` adds whenever `RUST_BACKTRACE` is set
+                eprintln!("Error: {e:#}");
                 process::exit(1);
             }
         }
@@ -47,6 +47,8 @@ fn main() {
                 }
             }
         }
+        Some(Commands::Css) => print!("{}", THEME_CSS),
+        Some(Commands::Js) => print!("{}", CLIENT_JS),
     }
 }
 
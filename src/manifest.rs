@@ -0,0 +1,225 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use mdbook_preprocessor::errors::Result;
+
+pub static MANIFEST_FILE_NAME: &str = ".mdbook-graphviz-manifest";
+
+/// The list of files we generated on our last run, used to find files that are no longer generated
+pub struct Manifest {
+    root: PathBuf,
+    files: BTreeSet<PathBuf>,
+}
+
+impl Manifest {
+    /// Load the manifest from our book root, an empty one is returned if we haven't run before
+    pub fn load(root: &Path) -> Result<Manifest> {
+        let files = match fs::read_to_string(root.join(MANIFEST_FILE_NAME)) {
+            Ok(contents) => contents.lines().map(PathBuf::from).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Manifest {
+            root: root.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Replace our previously generated files with the ones from this run, removing any that are
+    /// stale. Stale files that aren't removed stay in the manifest so that a later run can.
+    pub fn update(
+        &mut self,
+        generated_files: &[PathBuf],
+        remove: bool,
+        dry_run: bool,
+    ) -> Result<()> {
+        let generated_files = generated_files
+            .iter()
+            .map(|path| self.relative_path(path))
+            .collect::<BTreeSet<_>>();
+
+        let mut files = generated_files.clone();
+        for stale_file in self.files.difference(&generated_files) {
+            let path = self.root.join(stale_file);
+            if !remove {
+                files.insert(stale_file.clone());
+            } else if dry_run {
+                eprintln!("Would remove stale generated file: {}", path.display());
+                files.insert(stale_file.clone());
+            } else {
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        self.files = files;
+
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let manifest_path = self.root.join(MANIFEST_FILE_NAME);
+        if self.files.is_empty() {
+            // don't leave a manifest behind for books that aren't generating files
+            match fs::remove_file(manifest_path) {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        } else {
+            let contents = self
+                .files
+                .iter()
+                .map(|path| format!("{}\n", path.display()))
+                .collect::<String>();
+
+            Ok(fs::write(manifest_path, contents)?)
+        }
+    }
+
+    /// Store paths relative to our book root so that the book can be moved around
+    fn relative_path(&self, path: &Path) -> PathBuf {
+        let path = normalize_path(path);
+        match path.strip_prefix(normalize_path(&self.root)) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path,
+        }
+    }
+}
+
+/// Resolve `.` and `..` components without touching the file system
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// The path to `path` from the directory `base`, resolved lexically like `normalize_path`
+pub fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let path = normalize_path(path);
+    let base = normalize_path(base);
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    base.components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .chain(path.components().skip(common))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from("test-output").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn generate(dir: &Path, names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, "").unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            normalize_path(Path::new("/book/./src/sub/../out/a.svg")),
+            PathBuf::from("/book/src/out/a.svg")
+        );
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(
+            relative_to(
+                Path::new("/book/src/guide"),
+                Path::new("/book/src/guide/../graphviz-out/guide")
+            ),
+            PathBuf::from("../../guide")
+        );
+        assert_eq!(
+            relative_to(Path::new("/book/src"), Path::new("/book/src")),
+            PathBuf::new()
+        );
+    }
+
+    #[test]
+    fn remove_stale_files() {
+        let dir = test_dir("manifest-remove");
+        let first_run = generate(&dir, &["a.svg", "b.svg"]);
+
+        let mut manifest = Manifest::load(&dir).unwrap();
+        manifest.update(&first_run, true, false).unwrap();
+        manifest.save().unwrap();
+
+        let mut manifest = Manifest::load(&dir).unwrap();
+        manifest.update(&first_run[..1], true, false).unwrap();
+        manifest.save().unwrap();
+
+        assert!(dir.join("a.svg").exists());
+        assert!(!dir.join("b.svg").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join(MANIFEST_FILE_NAME)).unwrap(),
+            "a.svg\n"
+        );
+    }
+
+    #[test]
+    fn dry_run() {
+        let dir = test_dir("manifest-dry-run");
+        let first_run = generate(&dir, &["a.svg", "b.svg"]);
+
+        let mut manifest = Manifest::load(&dir).unwrap();
+        manifest.update(&first_run, true, false).unwrap();
+        manifest.update(&first_run[..1], true, true).unwrap();
+        manifest.save().unwrap();
+
+        assert!(dir.join("b.svg").exists());
+        // we should still remember our stale file for the next real run
+        assert_eq!(
+            fs::read_to_string(dir.join(MANIFEST_FILE_NAME)).unwrap(),
+            "a.svg\nb.svg\n"
+        );
+    }
+
+    #[test]
+    fn no_generated_files() {
+        let dir = test_dir("manifest-empty");
+
+        let mut manifest = Manifest::load(&dir).unwrap();
+        manifest.update(&[], true, false).unwrap();
+        manifest.save().unwrap();
+
+        assert!(!dir.join(MANIFEST_FILE_NAME).exists());
+    }
+}
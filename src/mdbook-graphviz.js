@@ -0,0 +1,77 @@
+/*
+ * Renders the diagrams left in the page by `render = "client"`. Each placeholder holds its DOT
+ * source, which is rendered with viz.js loaded from the placeholder's `data-viz-url`.
+ */
+(function () {
+    "use strict";
+
+    const diagrams = document.querySelectorAll(".mdbook-graphviz-client");
+    if (diagrams.length === 0) {
+        return;
+    }
+
+    // relative URLs are relative to the book's root, like the rest of mdBook's assets
+    function resolveUrl(url) {
+        if (/^([a-z][a-z0-9+.-]*:|\/)/i.test(url) || typeof path_to_root === "undefined") {
+            return url;
+        }
+        return path_to_root + url;
+    }
+
+    function loadViz(url) {
+        if (window.Viz) {
+            return Promise.resolve(window.Viz);
+        }
+
+        return new Promise(function (resolve, reject) {
+            const script = document.createElement("script");
+            script.src = resolveUrl(url);
+            script.onload = function () {
+                resolve(window.Viz);
+            };
+            script.onerror = function () {
+                reject(new Error("Couldn't load viz.js from " + url));
+            };
+            document.head.appendChild(script);
+        });
+    }
+
+    function showError(diagram, error) {
+        const message = document.createElement("pre");
+        message.className = "mdbook-graphviz-error";
+        message.textContent = String(error);
+        diagram.appendChild(message);
+    }
+
+    function render(viz, diagram) {
+        const source = diagram.querySelector(".mdbook-graphviz-source");
+        const svg = viz.renderSVGElement(source.textContent, {
+            engine: diagram.dataset.engine,
+        });
+        // scale to our width, the viewBox keeps our aspect ratio
+        if (diagram.dataset.width) {
+            svg.setAttribute("width", diagram.dataset.width);
+            svg.removeAttribute("height");
+        }
+        source.replaceWith(svg);
+    }
+
+    loadViz(diagrams[0].dataset.vizUrl)
+        .then(function (Viz) {
+            return Viz.instance();
+        })
+        .then(function (viz) {
+            diagrams.forEach(function (diagram) {
+                try {
+                    render(viz, diagram);
+                } catch (error) {
+                    showError(diagram, error);
+                }
+            });
+        })
+        .catch(function (error) {
+            diagrams.forEach(function (diagram) {
+                showError(diagram, error);
+            });
+        });
+})();
@@ -0,0 +1,79 @@
+use mdbook_preprocessor::errors::{Error, Result};
+
+/// Identifies our output in cache keys in place of the Graphviz version, our own version is part
+/// of it since we post-process what layout-rs draws
+pub static NATIVE_VERSION: &str = concat!(
+    "layout-rs 0.1.3, mdbook-graphviz ",
+    env!("CARGO_PKG_VERSION")
+);
+
+/// Lay out and draw our code as an SVG in-process, without the Graphviz command line tools
+#[cfg(feature = "native")]
+pub fn render_svg(code: &str) -> Result<Vec<u8>> {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use layout::backends::svg::SVGWriter;
+    use layout::gv::{DotParser, GraphBuilder};
+
+    let graph = DotParser::new(code)
+        .process()
+        .map_err(|e| Error::msg(format!("layout-rs couldn't parse the graph: {e}")))?;
+
+    // layout-rs panics on the graphs it can't lay out rather than returning an error
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut visual_graph = builder.get();
+
+        let mut svg = SVGWriter::new();
+        visual_graph.do_it(false, false, false, &mut svg);
+
+        svg.finalize().into_bytes()
+    }))
+    .map_err(|_| Error::msg("layout-rs couldn't lay out the graph"))
+}
+
+#[cfg(not(feature = "native"))]
+pub fn render_svg(_code: &str) -> Result<Vec<u8>> {
+    Err(Error::msg(
+        "mdbook-graphviz was built without the `native` feature",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn native_version_matches_dependency() {
+        let manifest = include_str!("../Cargo.toml");
+        let pinned = manifest
+            .lines()
+            .find_map(|line| line.strip_prefix("layout-rs = { version = \"="))
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+
+        assert!(
+            NATIVE_VERSION.starts_with(&format!("layout-rs {pinned},")),
+            "{NATIVE_VERSION}"
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn render() {
+        use crate::svg::{SvgOptions, rewrite_svg};
+
+        let svg = String::from_utf8(render_svg("digraph { a -> b }").unwrap()).unwrap();
+
+        assert!(svg.contains("<svg"), "{svg}");
+        // we need to be able to inline it
+        rewrite_svg(&svg, &SvgOptions::default()).unwrap();
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn syntax_error() {
+        assert!(render_svg("digraph { a -> }").is_err());
+    }
+}
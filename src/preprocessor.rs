@@ -1,47 +1,107 @@
-use crate::renderer::{CLIGraphviz, CLIGraphvizToFile, GraphvizRenderer};
+use crate::attributes::{ATTRIBUTE_KEYS, BlockAttributes};
+use crate::config::{
+    DEFAULT_ENGINE, FileNames, GRAPHVIZ_ENGINES, GraphvizConfig, OnError, RenderMode,
+    format_extension, validate_engine, validate_formats,
+};
+use crate::manifest::{Manifest, normalize_path, relative_to};
+use crate::renderer::{
+    CLIGraphviz, CLIGraphvizToFile, ClientGraphviz, GraphvizError, GraphvizRenderer, escape_html,
+    format_error, with_max_concurrency,
+};
+use crate::source_files::render_source_files;
+use crate::svg::markdown_link_path;
+use crate::{CLIENT_JS, THEME_CSS};
 use async_recursion::async_recursion;
 use core::mem;
-use futures::future;
+use futures::{TryFutureExt, future};
 use mdbook_markdown::pulldown_cmark::CodeBlockKind::Fenced;
-use mdbook_markdown::pulldown_cmark::{Event, Tag, TagEnd};
+use mdbook_markdown::pulldown_cmark::{self, CowStr, Event, Parser, Tag, TagEnd};
 use mdbook_markdown::{MarkdownOptions, new_cmark_parser};
-use mdbook_preprocessor::book::{Book, BookItem, Chapter};
-use mdbook_preprocessor::errors::Result;
+use mdbook_preprocessor::book::{Book, BookItem, Chapter, SectionNumber};
+use mdbook_preprocessor::errors::{Error, Result};
 use mdbook_preprocessor::{Preprocessor, PreprocessorContext};
 use pulldown_cmark_to_cmark::cmark;
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
 use std::future::Future;
-use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::runtime::Runtime;
 use toml::Table;
 
 pub static PREPROCESSOR_NAME: &str = "graphviz";
-pub static DEFAULT_INFO_STRING_PREFIX: &str = "dot process";
 
-pub struct GraphvizConfig {
-    pub output_to_file: bool,
-    pub link_to_file: bool,
-    pub info_string: String,
-    pub arguments: Vec<String>,
-}
-
-impl Default for GraphvizConfig {
-    fn default() -> Self {
-        Self {
-            output_to_file: false,
-            link_to_file: false,
-            info_string: DEFAULT_INFO_STRING_PREFIX.to_string(),
-            arguments: vec![String::from("-Tsvg")],
+/// Ignores everything in our output directory, including itself
+static OUTPUT_DIR_GITIGNORE: &str =
+    "# Generated by mdbook-graphviz, everything here is rebuilt from the book\n*\n";
+
+/// Our script for `render = "client"`
+static CLIENT_JS_ASSET: BookAsset = BookAsset {
+    purpose: "Diagrams are rendered in the browser",
+    setting: "additional-js",
+    file_name: "mdbook-graphviz.js",
+    contents: CLIENT_JS,
+};
+
+/// Our stylesheet for `theme-aware = true`
+static THEME_CSS_ASSET: BookAsset = BookAsset {
+    purpose: "Theme-aware diagrams are colored",
+    setting: "additional-css",
+    file_name: "mdbook-graphviz.css",
+    contents: THEME_CSS,
+};
+
+impl OnError {
+    /// Turn a rendering error into the events we should output instead, or keep failing
+    fn recover<'a>(
+        self,
+        error: Error,
+        info_string: CowStr<'a>,
+        code: String,
+    ) -> Result<Vec<Event<'a>>> {
+        if self == OnError::Fail {
+            return Err(error);
         }
+
+        eprintln!("Warning: {error:#}");
+
+        Ok(match self {
+            OnError::Inline => vec![
+                Event::Start(Tag::HtmlBlock),
+                Event::Html(format_error(&error, &code).into()),
+                Event::End(TagEnd::HtmlBlock),
+            ],
+            _ => vec![
+                Event::Start(Tag::CodeBlock(Fenced(info_string))),
+                Event::Text(format!("{code}\n").into()),
+                Event::End(TagEnd::CodeBlock),
+            ],
+        })
     }
 }
 
+/// Our mdBook preprocessor, rendering with the Graphviz CLI
 pub struct GraphvizPreprocessor;
 
+/// Processes the Graphviz blocks in a book with a renderer
 pub struct Graphviz<R: GraphvizRenderer> {
     src_dir: PathBuf,
+    /// Files included with `src` must be inside of this directory
+    root_dir: PathBuf,
+    /// The directories `mdbook serve` watches for changes
+    watch_dirs: Vec<PathBuf>,
     config: GraphvizConfig,
-    _phantom: PhantomData<*const R>,
+    renderer: R,
+    /// Every file our renderer generates during this run
+    generated_files: Mutex<BTreeMap<PathBuf, String>>,
+    /// The chapters in our book, for checking the links in our diagrams
+    chapters: Option<BTreeSet<PathBuf>>,
+    /// Every diagram in our book that can be referenced, by its reference name
+    figures: BTreeMap<String, Figure>,
 }
 
 impl Preprocessor for GraphvizPreprocessor {
@@ -50,76 +110,195 @@ impl Preprocessor for GraphvizPreprocessor {
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let mut config = GraphvizConfig::default();
+        let mut config = match ctx.config.preprocessors::<Table>()?.get(self.name()) {
+            Some(ctx_config) => GraphvizConfig::from_table(ctx_config.clone())?,
+            None => GraphvizConfig::default(),
+        };
+        config.cache_dir = config.cache_dir.map(|cache_dir| ctx.root.join(cache_dir));
 
-        if let Some(ctx_config) = ctx.config.preprocessors::<Table>()?.get(self.name()) {
-            if let Some(value) = ctx_config.get("output-to-file") {
-                config.output_to_file = value
-                    .as_bool()
-                    .expect("output-to-file option is required to be a boolean");
+        let src_dir = ctx.root.clone().join(&ctx.config.book.src);
+        let watch_dirs = ctx
+            .config
+            .build
+            .extra_watch_dirs
+            .iter()
+            .map(|dir| ctx.root.join(dir))
+            .collect::<Vec<_>>();
+        let clean_stale_files = config.clean_stale_files;
+        let clean_dry_run = config.clean_dry_run;
+
+        if ctx.renderer == "html" {
+            let html = ctx.config.html_config().unwrap_or_default();
+            if config.render == RenderMode::Client {
+                write_book_asset(&ctx.root, &html.additional_js, &CLIENT_JS_ASSET)?;
             }
-
-            if let Some(value) = ctx_config.get("link-to-file") {
-                config.link_to_file = value
-                    .as_bool()
-                    .expect("link-to-file option is required to be a boolean");
+            if config.theme_aware {
+                write_book_asset(&ctx.root, &html.additional_css, &THEME_CSS_ASSET)?;
             }
+        }
 
-            if let Some(value) = ctx_config.get("info-string") {
-                config.info_string = value
-                    .as_str()
-                    .expect("info-string option is required to be a string")
-                    .to_string();
-            }
+        let generated_files = new_runtime().block_on(async {
+            // standalone Graphviz files are rendered to images next to them
+            let mut generated_files = if config.render_source_files {
+                render_source_files(&src_dir, &config).await?
+            } else {
+                vec![]
+            };
+
+            if config.render == RenderMode::Client {
+                let graphviz = Graphviz::new(ClientGraphviz, src_dir, config)
+                    .with_root(ctx.root.clone(), watch_dirs)
+                    .with_book(&book);
+                graphviz.process_book(&mut book).await?;
+            } else if config.output_to_file {
+                // renderers only copy files from the book source and clear their own output
+                // first, so our files have to live in the source but can stay out of git
+                if let Some(output_dir) = &config.output_dir {
+                    generated_files.push(ignore_output_dir(&src_dir.join(output_dir))?);
+                }
 
-            if let Some(value) = ctx_config.get("arguments") {
-                config.arguments = value
-                    .as_array()
-                    .expect("arguments option is required to be an array")
-                    .iter()
-                    .map(|v| {
-                        String::from(
-                            v.as_str()
-                                .expect("arguments option is required to contain strings"),
-                        )
-                    })
-                    .collect()
+                let graphviz = Graphviz::new(CLIGraphvizToFile, src_dir, config)
+                    .with_root(ctx.root.clone(), watch_dirs)
+                    .with_book(&book);
+                graphviz.process_book(&mut book).await?;
+                generated_files.extend(graphviz.into_generated_files().into_keys());
+            } else {
+                let graphviz = Graphviz::new(CLIGraphviz, src_dir, config)
+                    .with_root(ctx.root.clone(), watch_dirs)
+                    .with_book(&book);
+                graphviz.process_book(&mut book).await?;
+                generated_files.extend(graphviz.into_generated_files().into_keys());
             }
-        }
 
-        let src_dir = ctx.root.clone().join(&ctx.config.book.src);
+            Ok::<_, Error>(generated_files)
+        })?;
 
-        // we really only need 1 thread since we're just calling out to the Graphviz CLI
-        tokio::runtime::Builder::new_current_thread()
-            .enable_io()
-            .build()
-            .unwrap()
-            .block_on(async {
-                if config.output_to_file {
-                    Graphviz::<CLIGraphvizToFile>::new(src_dir, config)
-                        .process_sub_items(&mut book.items)
-                        .await
-                } else {
-                    Graphviz::<CLIGraphviz>::new(src_dir, config)
-                        .process_sub_items(&mut book.items)
-                        .await
-                }
-            })?;
+        let mut manifest = Manifest::load(&ctx.root)?;
+        manifest.update(&generated_files, clean_stale_files, clean_dry_run)?;
+        manifest.save()?;
 
         Ok(book)
     }
 }
 
 impl<R: GraphvizRenderer> Graphviz<R> {
-    pub fn new(src_dir: PathBuf, config: GraphvizConfig) -> Graphviz<R> {
+    pub fn new(renderer: R, src_dir: PathBuf, config: GraphvizConfig) -> Graphviz<R> {
         Self {
+            root_dir: src_dir.clone(),
+            watch_dirs: vec![src_dir.clone()],
             src_dir,
             config,
-            _phantom: PhantomData,
+            renderer,
+            generated_files: Mutex::new(BTreeMap::new()),
+            chapters: None,
+            figures: BTreeMap::new(),
+        }
+    }
+
+    /// Every file our renderer generated, along with the block that generated it
+    fn into_generated_files(self) -> BTreeMap<PathBuf, String> {
+        self.generated_files.into_inner().unwrap()
+    }
+
+    /// Render every Graphviz block in our book's chapters
+    pub async fn process_book(&self, book: &mut Book) -> Result<()> {
+        // every block renders at once, so limit how many Graphviz processes that starts
+        with_max_concurrency(
+            self.config.concurrency_limit(),
+            self.process_sub_items(&mut book.items),
+        )
+        .await
+    }
+
+    /// Allow including files from anywhere in our book's root directory, along with the extra
+    /// directories watched by `mdbook serve`
+    pub fn with_root(mut self, root_dir: PathBuf, extra_watch_dirs: Vec<PathBuf>) -> Self {
+        self.root_dir = root_dir;
+        self.watch_dirs = [self.src_dir.clone()]
+            .into_iter()
+            .chain(extra_watch_dirs)
+            .collect();
+        self
+    }
+
+    /// Warn about links from our diagrams to anything other than these chapters
+    pub fn with_chapters<I: IntoIterator<Item = PathBuf>>(mut self, chapters: I) -> Self {
+        self.chapters = Some(
+            chapters
+                .into_iter()
+                .map(|path| normalize_path(&path))
+                .collect(),
+        );
+        self
+    }
+
+    /// Find the chapters and diagrams in our whole book, so that any chapter can reference them
+    pub fn with_book(mut self, book: &Book) -> Self {
+        let mut figures = BTreeMap::<String, Figure>::new();
+        for chapter in book.chapters() {
+            let Some(chapter_path) = &chapter.path else {
+                continue;
+            };
+            let chapter_name = chapter.name.trim();
+
+            let (content, _) = self.expand_includes(&chapter.content);
+            let mut index = 0;
+            let mut anchors = BTreeSet::new();
+            for event in new_cmark_parser(&content, &MarkdownOptions::default()) {
+                let Event::Start(Tag::CodeBlock(Fenced(info_string))) = event else {
+                    continue;
+                };
+                let Some((_, rest)) = self.match_info_string(&info_string) else {
+                    continue;
+                };
+                // any invalid blocks are reported when we process their chapter, they don't get an
+                // anchor but still count towards the anchors of later ones
+                let Ok((attributes, _)) = BlockAttributes::parse(rest) else {
+                    index += 1;
+                    continue;
+                };
+                let figure = Figure {
+                    chapter_path: chapter_path.clone(),
+                    anchor: unique_anchor(
+                        &figure_anchor(&attributes, chapter_name, index),
+                        &mut anchors,
+                    ),
+                    label: if self.config.figure_numbers {
+                        format!("Figure {}", figure_number(chapter.number.as_ref(), index))
+                    } else {
+                        attributes
+                            .name
+                            .clone()
+                            .or(attributes.id.clone())
+                            .unwrap_or_default()
+                    },
+                    description: format!("chapter `{chapter_name}`"),
+                };
+                index += 1;
+
+                let Some(key) = reference_key(&attributes) else {
+                    continue;
+                };
+                if let Some(other) = figures.get(&key) {
+                    eprintln!(
+                        "Warning: The diagram reference `{key}` is used in {} and {}, references will link to the first",
+                        other.description, figure.description
+                    );
+                    continue;
+                }
+                figures.insert(key, figure);
+            }
         }
+        self.figures = figures;
+
+        let chapters = book
+            .chapters()
+            .filter_map(|chapter| chapter.path.clone())
+            .collect::<Vec<_>>();
+        self.with_chapters(chapters)
     }
 
-    #[async_recursion(?Send)]
+    #[async_recursion]
     async fn process_sub_items(&'async_recursion self, items: &mut Vec<BookItem>) -> Result<()> {
         let mut item_futures = Vec::with_capacity(items.len());
         for item in mem::take(items) {
@@ -144,7 +323,7 @@ impl<R: GraphvizRenderer> Graphviz<R> {
         Ok(())
     }
 
-    #[async_recursion(?Send)]
+    #[async_recursion]
     async fn process_chapter(&self, mut chapter: Chapter) -> Result<Chapter> {
         // make sure to process our chapter sub-items
         self.process_sub_items(&mut chapter.sub_items).await?;
@@ -157,55 +336,196 @@ impl<R: GraphvizRenderer> Graphviz<R> {
         let mut chapter_path = self.src_dir.join(chapter.path.as_ref().unwrap());
         // remove the chapter filename
         chapter_path.pop();
+        let output_dir = self.output_dir(chapter.path.as_ref().unwrap());
 
-        let mut buf = String::with_capacity(chapter.content.len());
+        // the file we name in our errors, relative to the book source
+        let source_file = chapter
+            .source_path
+            .as_ref()
+            .or(chapter.path.as_ref())
+            .unwrap()
+            .display()
+            .to_string();
+
+        // references go first since they change the length of lines, which would throw off the
+        // offsets of the lines our includes add
+        let content = self.resolve_references(
+            &chapter.content,
+            chapter.path.as_ref().unwrap(),
+            &source_file,
+        );
+        let (content, added_lines) = self.expand_includes(&content);
+        let mut buf = String::with_capacity(content.len());
         let mut graphviz_block_builder: Option<GraphvizBlockBuilder> = None;
+        // a block we can't render, along with its code so far
+        let mut invalid_block: Option<(Error, String)> = None;
+        // keep the original info string around in case we need to put the block back
+        let mut graphviz_info_string = None;
         let mut image_index = 0;
+        // the lines of our blocks without a text alternative
+        let mut missing_alt_lines = vec![];
+        // the HTML anchors of our blocks so far, which have to be unique within the page
+        let mut anchors = BTreeSet::new();
 
-        let events = new_cmark_parser(&chapter.content, &MarkdownOptions::default());
-        let mut event_futures = Vec::<Pin<Box<dyn Future<Output = _>>>>::new();
+        let events = new_cmark_parser(&content, &MarkdownOptions::default()).into_offset_iter();
+        let mut event_futures = Vec::<Pin<Box<dyn Future<Output = _> + Send>>>::new();
 
-        for e in events {
-            if let Some(mut builder) = graphviz_block_builder.take() {
+        for (e, range) in events {
+            if let Some((error, mut code)) = invalid_block.take() {
+                match e {
+                    Event::Text(ref text) => {
+                        code.push_str(text);
+                        invalid_block = Some((error, code));
+                    }
+                    Event::End(TagEnd::CodeBlock) => {
+                        let info_string = graphviz_info_string.take().unwrap_or("".into());
+                        event_futures.push(Box::pin(future::ready(self.config.on_error.recover(
+                            error,
+                            info_string,
+                            code.trim_end().to_string(),
+                        ))));
+                    }
+                    _ => {
+                        invalid_block = Some((error, code));
+                    }
+                }
+            } else if let Some(mut builder) = graphviz_block_builder.take() {
                 match e {
                     Event::Text(ref text) => {
                         builder.append_code(text.to_string());
                         graphviz_block_builder = Some(builder);
                     }
                     Event::End(TagEnd::CodeBlock) => {
+                        let info_string = graphviz_info_string.take().unwrap_or("".into());
+                        if let Some(src) = builder.attributes.src.clone()
+                            && let Err(e) = self.include_source(
+                                &mut builder,
+                                &src,
+                                chapter.path.as_ref().unwrap(),
+                            )
+                        {
+                            let e = e.context(format!(
+                                "Invalid Graphviz block at {source_file}:{}",
+                                builder.line
+                            ));
+                            image_index += 1;
+                            event_futures.push(Box::pin(future::ready(
+                                self.config.on_error.recover(
+                                    e,
+                                    info_string,
+                                    builder.code.trim_end().to_string(),
+                                ),
+                            )));
+                            continue;
+                        }
+
                         // finish our digraph
-                        let block = builder.build(image_index);
+                        let mut block = builder.build(image_index, self.config.file_names);
                         image_index += 1;
-
-                        event_futures.push(Box::pin(R::render_graphviz(block, &self.config)));
+                        let anchor = unique_anchor(&block.anchor, &mut anchors);
+                        if anchor != block.anchor {
+                            eprintln!(
+                                "Warning: {} has the same anchor `{}` as another diagram in its chapter, it uses `{anchor}` instead, give it a unique `id` attribute",
+                                block.description(),
+                                block.anchor
+                            );
+                            block.anchor = anchor;
+                        }
+                        if let Err(e) = self.track_output_files(&block) {
+                            event_futures.push(Box::pin(future::ready(
+                                self.config.on_error.recover(e, info_string, block.code),
+                            )));
+                            continue;
+                        }
+                        for (path, code_line) in
+                            self.missing_chapter_links(&block, chapter.path.as_ref().unwrap())
+                        {
+                            let (code_file, code_offset) = block.code_origin(&source_file);
+                            eprintln!(
+                                "Warning: {} links to `{path}` which isn't a chapter in this book, at {code_file}:{}",
+                                block.description(),
+                                code_offset + code_line
+                            );
+                        }
+                        if block.alt_text().is_none() {
+                            missing_alt_lines.push(block.line);
+                        }
+
+                        let description = block.description();
+                        let line = block.line;
+                        let code_origin = block.code_origin(&source_file);
+                        let source_file = source_file.clone();
+                        let code = block.code.clone();
+                        let caption = self.caption(&block, chapter.number.as_ref());
+                        let figures = self.config.figures;
+                        event_futures.push(Box::pin(
+                            self.renderer
+                                .render_graphviz(block, &self.config)
+                                .map_ok(move |events| {
+                                    format_figure(events, caption.as_deref(), figures)
+                                })
+                                .map_err(move |e| {
+                                    render_error(e, &description, &source_file, line, code_origin)
+                                })
+                                .or_else(move |e| {
+                                    future::ready(self.config.on_error.recover(
+                                        e,
+                                        info_string,
+                                        code,
+                                    ))
+                                }),
+                        ));
                     }
                     _ => {
                         graphviz_block_builder = Some(builder);
                     }
                 }
             } else {
-                if let Event::Start(Tag::CodeBlock(Fenced(info_string))) = &e {
-                    let prefix_len = self.config.info_string.len();
-                    // The following split is safe because the characters have
-                    // to be byte equal to be a match, therefore we are
-                    // guaranteed to split at a character boundary.
-                    let (prefix, graph_name) =
-                        info_string.split_at(std::cmp::min(info_string.len(), prefix_len));
-                    if prefix == self.config.info_string {
-                        // check if we can have a name at the end of our info string
-                        graphviz_block_builder = Some(GraphvizBlockBuilder::new(
-                            chapter_path.clone(),
-                            chapter.name.clone().trim().to_string(),
-                            graph_name.trim().to_string(),
-                        ));
-                        continue;
+                if let Event::Start(Tag::CodeBlock(Fenced(info_string))) = &e
+                    && let Some((engine, graph_name)) = self.match_info_string(info_string)
+                {
+                    // our included blocks take up an extra line in our content
+                    let line = line_number(&content, range.start)
+                        - added_lines
+                            .iter()
+                            .filter(|offset| **offset < range.start)
+                            .count();
+                    match self.block_builder(engine, graph_name, &source_file, line) {
+                        Ok(mut builder) => {
+                            builder.path = chapter_path.clone();
+                            builder.output_dir = output_dir.clone();
+                            builder.chapter_name = chapter.name.clone().trim().to_string();
+
+                            graphviz_block_builder = Some(builder);
+                        }
+                        Err(e) => {
+                            let e = e
+                                .context(format!("Invalid Graphviz block at {source_file}:{line}"));
+                            // invalid blocks still count towards the anchors of later ones
+                            image_index += 1;
+                            invalid_block = Some((e, String::new()));
+                        }
                     }
+                    graphviz_info_string = Some(info_string.clone());
+                    continue;
                 }
                 // pass through all events that don't impact our Graphviz block
                 event_futures.push(Box::pin(async { Ok(vec![e]) }));
             }
         }
 
+        if self.config.warn_missing_alt && !missing_alt_lines.is_empty() {
+            eprintln!(
+                "Warning: Diagrams in chapter `{}` have no text alternative for readers who can't see them, add an `alt` attribute at {}",
+                chapter.name.trim(),
+                missing_alt_lines
+                    .iter()
+                    .map(|line| format!("{source_file}:{line}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
         let mut events = future::join_all(event_futures)
             .await
             .into_iter()
@@ -219,60 +539,620 @@ impl<R: GraphvizRenderer> Graphviz<R> {
 
         Ok(chapter)
     }
+
+    /// Start building a block from the rest of its info string
+    fn block_builder(
+        &self,
+        engine: Option<&str>,
+        rest: &str,
+        source_file: &str,
+        line: usize,
+    ) -> Result<GraphvizBlockBuilder> {
+        let (attributes, unknown_keys) = BlockAttributes::parse(rest)?;
+        for key in unknown_keys {
+            eprintln!(
+                "Warning: Ignoring unknown attribute `{key}` at {source_file}:{line}, expected one of: {}",
+                ATTRIBUTE_KEYS.join(", ")
+            );
+        }
+
+        let engine = attributes
+            .engine
+            .as_deref()
+            .or(engine)
+            .unwrap_or(&self.config.engine);
+        validate_engine(engine)?;
+        let formats = attributes
+            .formats
+            .clone()
+            .unwrap_or_else(|| self.config.formats.clone());
+        validate_formats(&formats)?;
+
+        Ok(GraphvizBlockBuilder {
+            path: PathBuf::new(),
+            output_dir: PathBuf::new(),
+            chapter_name: String::new(),
+            graph_name: attributes.name.clone().unwrap_or_default(),
+            id: attributes.id.clone(),
+            engine: engine.to_string(),
+            formats,
+            attributes,
+            line,
+            code: String::new(),
+            source: None,
+        })
+    }
+
+    /// Read the file a block includes with its `src` attribute in place of its code
+    fn include_source(
+        &self,
+        builder: &mut GraphvizBlockBuilder,
+        src: &str,
+        chapter_path: &Path,
+    ) -> Result<()> {
+        if !builder.code.trim().is_empty() {
+            return Err(Error::msg(format!(
+                "The block includes `{src}` so it can't have any code of its own"
+            )));
+        }
+
+        let chapter_dir = chapter_path.parent().unwrap_or(Path::new(""));
+        let path = normalize_path(&self.src_dir.join(chapter_dir).join(src));
+        if !path.starts_with(normalize_path(&self.root_dir)) {
+            return Err(Error::msg(format!(
+                "The included file `{src}` is outside of the book's root directory"
+            )));
+        }
+        let code = std::fs::read_to_string(&path)
+            .map_err(|e| Error::new(e).context(format!("Couldn't read `{}`", path.display())))?;
+
+        if !self
+            .watch_dirs
+            .iter()
+            .any(|dir| path.starts_with(normalize_path(dir)))
+        {
+            eprintln!(
+                "Warning: `{}` isn't in a directory watched by `mdbook serve`, add its directory to `build.extra-watch-dirs` to rebuild when it changes",
+                path.display()
+            );
+        }
+
+        builder.code = code;
+        builder.source = Some(relative_to(&path, &self.src_dir));
+
+        Ok(())
+    }
+
+    /// Replace each `{{#graphviz path/to/file.dot attributes}}` on a line of its own with a block
+    /// including that file, these can be escaped with a backslash like `\{{#graphviz path}}`.
+    /// Returns the offsets of the lines we added, since a block takes up an extra line.
+    fn expand_includes(&self, content: &str) -> (String, Vec<usize>) {
+        lazy_static! {
+            static ref INCLUDE_RE: Regex =
+                Regex::new(r"(?m)^([ \t]*)(\\)?\{\{#graphviz\s+(\S+)\s*(.*?)\s*\}\}[ \t]*$")
+                    .unwrap();
+        }
+
+        let mut expanded = String::with_capacity(content.len());
+        let mut added_lines = vec![];
+        let mut last = 0;
+        for captures in INCLUDE_RE.captures_iter(content) {
+            let directive = captures.get(0).unwrap();
+            expanded.push_str(&content[last..directive.start()]);
+            last = directive.end();
+
+            let indent = &captures[1];
+            if captures.get(2).is_some() {
+                expanded.push_str(indent);
+                expanded.push_str(&directive.as_str()[indent.len() + 1..]);
+                continue;
+            }
+
+            let src = captures[3].replace('\\', "\\\\").replace('"', "\\\"");
+            expanded.push_str(&format!(
+                "{indent}```{} src=\"{src}\" {}",
+                self.config.info_string, &captures[4]
+            ));
+            added_lines.push(expanded.len());
+            expanded.push_str(&format!("\n{indent}```"));
+        }
+        expanded.push_str(&content[last..]);
+
+        (expanded, added_lines)
+    }
+
+    /// Remember the files this block generates, making sure no other block generates them too
+    fn track_output_files(&self, block: &GraphvizBlock) -> Result<()> {
+        let mut generated_files = self.generated_files.lock().unwrap();
+        for path in self.renderer.output_files(block) {
+            if let Some(other) = generated_files.get(&path) {
+                return Err(Error::msg(format!(
+                    "{} would overwrite `{}` which is generated by {other}, give one of them a unique `id` attribute",
+                    block.description(),
+                    path.display()
+                )));
+            }
+            generated_files.insert(path, block.description());
+        }
+
+        Ok(())
+    }
+
+    /// The HTML caption for a block, from its `caption` attribute or its graph name. Blocks are
+    /// only captioned by their name when we're numbering or wrapping them in figures.
+    fn caption(
+        &self,
+        block: &GraphvizBlock,
+        chapter_number: Option<&SectionNumber>,
+    ) -> Option<String> {
+        let caption = match &block.attributes.caption {
+            Some(caption) => inline_markdown_html(caption),
+            None if self.config.figures || self.config.figure_numbers => {
+                escape_html(&block.graph_name)
+            }
+            None => String::new(),
+        };
+
+        if self.config.figure_numbers {
+            let number = figure_number(chapter_number, block.index);
+            Some(if caption.is_empty() {
+                format!("Figure {number}")
+            } else {
+                format!("Figure {number}: {caption}")
+            })
+        } else {
+            (!caption.is_empty()).then_some(caption)
+        }
+    }
+
+    /// Replace each `{{#graphviz-ref name}}` with a link to that diagram, these can be escaped with
+    /// a backslash like `\{{#graphviz-ref name}}`
+    fn resolve_references(&self, content: &str, chapter_path: &Path, source_file: &str) -> String {
+        lazy_static! {
+            static ref REFERENCE_RE: Regex =
+                Regex::new(r"(\\)?\{\{#graphviz-ref\s+([^}]*?)\s*\}\}").unwrap();
+        }
+
+        let chapter_dir = chapter_path.parent().unwrap_or(Path::new(""));
+        REFERENCE_RE
+            .replace_all(content, |captures: &Captures| {
+                let reference = &captures[0];
+                if captures.get(1).is_some() {
+                    return reference[1..].to_string();
+                }
+
+                let name = &captures[2];
+                let Some(figure) = self.figures.get(&slugify(name)) else {
+                    eprintln!(
+                        "Warning: Unknown diagram reference `{name}` at {source_file}:{}",
+                        line_number(content, captures.get(0).unwrap().start())
+                    );
+                    return reference.to_string();
+                };
+
+                let url = if figure.chapter_path == chapter_path {
+                    format!("#{}", figure.anchor)
+                } else {
+                    let path = relative_to(&figure.chapter_path, chapter_dir);
+                    let path = path
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    format!("{path}#{}", figure.anchor)
+                };
+
+                let label = figure.label.replace('[', "\\[").replace(']', "\\]");
+                format!("[{label}]({url})")
+            })
+            .into_owned()
+    }
+
+    /// Find the links from our block to chapters that aren't in our book, along with their lines in
+    /// our block's code
+    fn missing_chapter_links(
+        &self,
+        block: &GraphvizBlock,
+        chapter_path: &Path,
+    ) -> Vec<(String, usize)> {
+        lazy_static! {
+            // the `URL` and `href` attributes, including the variants for edge heads, tails and labels
+            static ref LINK_RE: Regex = Regex::new(
+                r#"(?i)\b(?:head|tail|edge|label)?(?:url|href)\s*=\s*(?:"((?:[^"\\]|\\.)*)"|([^\s,;\]"]+))"#
+            )
+            .unwrap();
+        }
+
+        let Some(chapters) = &self.chapters else {
+            return vec![];
+        };
+        let chapter_dir = chapter_path.parent().unwrap_or(Path::new(""));
+
+        let mut missing = vec![];
+        for (line_offset, code_line) in block.code.lines().enumerate() {
+            for captures in LINK_RE.captures_iter(code_line) {
+                let href = captures.get(1).or(captures.get(2)).unwrap().as_str();
+                if let Some(path) = markdown_link_path(href)
+                    && !chapters.contains(&normalize_path(&chapter_dir.join(path)))
+                {
+                    missing.push((path.to_string(), line_offset + 1));
+                }
+            }
+        }
+
+        missing
+    }
+
+    /// Where the files for a chapter are written to, relative to the chapter's directory
+    fn output_dir(&self, chapter_path: &Path) -> PathBuf {
+        let Some(output_dir) = &self.config.output_dir else {
+            return PathBuf::new();
+        };
+
+        // mirror our chapter's directory inside the output directory to avoid collisions
+        let chapter_dir = chapter_path
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect::<PathBuf>();
+
+        let mut relative_output_dir = PathBuf::new();
+        for _ in chapter_dir.components() {
+            relative_output_dir.push("..");
+        }
+        relative_output_dir.push(output_dir);
+        relative_output_dir.push(chapter_dir);
+
+        relative_output_dir
+    }
+
+    /// Check if this info string marks a block for processing, returning the layout engine it
+    /// selects (if any) along with the remainder of the info string.
+    fn match_info_string<'i>(&self, info_string: &'i str) -> Option<(Option<&'i str>, &'i str)> {
+        if let Some(rest) = info_string.strip_prefix(&self.config.info_string) {
+            return Some((None, rest));
+        }
+
+        // an info string like `dot process` can swap `dot` for any of the other layout engines
+        let suffix = self.config.info_string.strip_prefix(DEFAULT_ENGINE)?;
+        GRAPHVIZ_ENGINES.iter().find_map(|engine| {
+            let rest = info_string.strip_prefix(engine)?.strip_prefix(suffix)?;
+
+            Some((Some(&info_string[..engine.len()]), rest))
+        })
+    }
+}
+
+/// The runtime we process the book on, we really only need 1 thread since we're just calling out to
+/// the Graphviz CLI, but we need timers for `timeout`
+pub(crate) fn new_runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap()
+}
+
+/// Write a `.gitignore` into our output directory so that its files are never committed, returning
+/// its path
+fn ignore_output_dir(output_dir: &Path) -> Result<PathBuf> {
+    let gitignore = output_dir.join(".gitignore");
+    write_if_changed(&gitignore, OUTPUT_DIR_GITIGNORE)?;
+
+    Ok(gitignore)
+}
+
+/// Keep the entries of `asset`'s setting named after it up to date with this build, so that the
+/// HTML renderer copies it into the book, returning their paths
+fn write_book_asset(root: &Path, entries: &[PathBuf], asset: &BookAsset) -> Result<Vec<PathBuf>> {
+    let BookAsset {
+        purpose,
+        setting,
+        file_name,
+        contents,
+    } = asset;
+
+    let paths = entries
+        .iter()
+        .filter(|path| path.file_name() == Some(OsStr::new(file_name)))
+        .map(|path| root.join(path))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        eprintln!(
+            "Warning: {purpose} by `{file_name}`, add `{setting} = [\"{file_name}\"]` under \
+             `[output.html]` in book.toml"
+        );
+    }
+    for path in &paths {
+        write_if_changed(path, contents)?;
+    }
+
+    Ok(paths)
+}
+
+/// Write `contents` to `path` unless it's already there, since rewriting an unchanged file would
+/// set off another rebuild in `mdbook serve`
+pub(crate) fn write_if_changed(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let contents = contents.as_ref();
+    if std::fs::read(path).ok().as_deref() != Some(contents) {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)
+            .map_err(|e| Error::new(e).context(format!("Couldn't write `{}`", path.display())))?;
+    }
+
+    Ok(())
+}
+
+/// A file we bundle that mdBook's HTML renderer loads through one of its `[output.html]` settings
+struct BookAsset {
+    /// What the file does, for when the book doesn't load it
+    purpose: &'static str,
+    /// The setting listing it, like `additional-js`
+    setting: &'static str,
+    file_name: &'static str,
+    contents: &'static str,
+}
+
+/// The 1-based line of the byte `offset` in `content`
+fn line_number(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+/// A diagram that other chapters can link to
+struct Figure {
+    /// Our chapter's path in the book source
+    chapter_path: PathBuf,
+    anchor: String,
+    /// The text of links to us
+    label: String,
+    description: String,
+}
+
+/// The name used to reference a block, from its `id` or its graph name
+fn reference_key(attributes: &BlockAttributes) -> Option<String> {
+    let key = slugify(attributes.id.as_ref().or(attributes.name.as_ref())?);
+
+    (!key.is_empty()).then_some(key)
+}
+
+/// The HTML anchor for a block, stable as long as its name or position doesn't change
+fn figure_anchor(attributes: &BlockAttributes, chapter_name: &str, index: usize) -> String {
+    match reference_key(attributes) {
+        Some(key) => format!("graphviz-{key}"),
+        None => format!("graphviz-{}-{}", slugify(chapter_name), index + 1),
+    }
+}
+
+/// Our `anchor`, with a `-2`, `-3`, ... suffix if it's already one of the `anchors` in our chapter,
+/// which it's then added to
+fn unique_anchor(anchor: &str, anchors: &mut BTreeSet<String>) -> String {
+    let mut unique = anchor.to_string();
+    let mut suffix = 1;
+    while anchors.contains(&unique) {
+        suffix += 1;
+        unique = format!("{anchor}-{suffix}");
+    }
+    anchors.insert(unique.clone());
+
+    unique
+}
+
+/// The number of a block within our book, like `3.2` for the second block in chapter 3
+fn figure_number(chapter_number: Option<&SectionNumber>, index: usize) -> String {
+    match chapter_number {
+        // section numbers are displayed with a trailing `.`, like `3.`
+        Some(chapter_number) => format!("{chapter_number}{}", index + 1),
+        None => (index + 1).to_string(),
+    }
+}
+
+/// Wrap our rendered diagram in a `<figure>`, otherwise follow it with any caption
+fn format_figure<'a>(
+    events: Vec<Event<'a>>,
+    caption: Option<&str>,
+    figure: bool,
+) -> Vec<Event<'a>> {
+    if figure {
+        let figcaption = caption
+            .map(|caption| format!("<figcaption>{caption}</figcaption>\n"))
+            .unwrap_or_default();
+
+        html_block(String::from("<figure class=\"mdbook-graphviz-figure\">\n"))
+            .into_iter()
+            .chain(events)
+            .chain(html_block(format!("{figcaption}</figure>\n")))
+            .collect()
+    } else if let Some(caption) = caption {
+        events
+            .into_iter()
+            .chain(html_block(format!(
+                "<p class=\"mdbook-graphviz-caption\">{caption}</p>\n"
+            )))
+            .collect()
+    } else {
+        events
+    }
+}
+
+fn html_block<'a>(html: String) -> [Event<'a>; 3] {
+    [
+        Event::Start(Tag::HtmlBlock),
+        Event::Html(html.into()),
+        Event::End(TagEnd::HtmlBlock),
+    ]
+}
+
+/// Render the inline Markdown of a caption to HTML
+fn inline_markdown_html(markdown: &str) -> String {
+    let events = Parser::new(markdown).filter(|event| {
+        !matches!(
+            event,
+            Event::Start(Tag::Paragraph) | Event::End(TagEnd::Paragraph)
+        )
+    });
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events);
+
+    html.trim_end().to_string()
+}
+
+/// Lowercase words separated by `-`, for anchors and reference names
+fn slugify(content: &str) -> String {
+    content
+        .split(|ch: char| !ch.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Add where a block lives in our book to an error from rendering it
+fn render_error(
+    error: Error,
+    description: &str,
+    source_file: &str,
+    line: usize,
+    (code_file, code_offset): (String, usize),
+) -> Error {
+    let mut message = format!("Failed to render {description} at {source_file}:{line}");
+    // translate Graphviz syntax errors into a line in the file our code came from
+    if let Some(graphviz_line) = error
+        .downcast_ref::<GraphvizError>()
+        .and_then(GraphvizError::syntax_error_line)
+    {
+        message.push_str(&format!(
+            ", Graphviz reported a syntax error at {code_file}:{}",
+            code_offset + graphviz_line
+        ));
+    }
+
+    error.context(message)
 }
 
 struct GraphvizBlockBuilder {
     path: PathBuf,
+    output_dir: PathBuf,
     chapter_name: String,
     graph_name: String,
+    id: Option<String>,
+    engine: String,
+    formats: Vec<String>,
+    attributes: BlockAttributes,
+    line: usize,
     code: String,
+    source: Option<PathBuf>,
 }
 
 impl GraphvizBlockBuilder {
-    fn new(path: PathBuf, chapter_name: String, graph_name: String) -> GraphvizBlockBuilder {
-        GraphvizBlockBuilder {
-            path,
-            chapter_name,
-            graph_name,
-            code: String::new(),
-        }
-    }
-
     fn append_code<S: Into<String>>(&mut self, code: S) {
         self.code.push_str(&code.into());
     }
 
-    fn build(self, index: usize) -> GraphvizBlock {
+    fn build(self, index: usize, file_names: FileNames) -> GraphvizBlock {
         let GraphvizBlockBuilder {
             chapter_name,
             graph_name,
+            id,
+            engine,
+            formats,
+            attributes,
+            line,
             code,
+            source,
             path,
+            output_dir,
         } = self;
-        let cleaned_code = code.trim();
+        // only trim the end so that Graphviz line numbers still match up with our chapter
+        let cleaned_code = code.trim_end();
+
+        let id = match (id, file_names) {
+            (Some(id), _) => Some(id),
+            (None, FileNames::Hash) => {
+                let hash = content_hash(cleaned_code);
+                if !graph_name.is_empty() {
+                    Some(format!("{graph_name}_{hash}"))
+                } else {
+                    Some(hash)
+                }
+            }
+            (None, FileNames::Index) => None,
+        };
+        let anchor = figure_anchor(&attributes, &chapter_name, index);
 
         GraphvizBlock {
             graph_name,
+            id,
+            anchor,
+            engine,
+            formats,
+            attributes,
             code: cleaned_code.into(),
             chapter_name,
             chapter_path: path,
+            output_dir,
             index,
+            line,
+            source,
         }
     }
 }
 
+/// A Graphviz block from a chapter, ready for rendering
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct GraphvizBlock {
     pub graph_name: String,
+    /// A stable identifier used to name our files instead of the block's index
+    pub id: Option<String>,
+    /// The HTML anchor for linking to our diagram
+    pub anchor: String,
+    pub engine: String,
+    /// The formats to render in file mode, there is always at least one
+    pub formats: Vec<String>,
+    /// The attributes as written in our info string
+    pub attributes: BlockAttributes,
     pub code: String,
     pub chapter_name: String,
     pub chapter_path: PathBuf,
+    /// Where generated files are written, relative to `chapter_path`
+    pub output_dir: PathBuf,
     pub index: usize,
+    /// The line in the chapter source where this block's fence begins
+    pub line: usize,
+    /// The file our code was included from with `src`, relative to the book source
+    pub source: Option<PathBuf>,
 }
 
 impl GraphvizBlock {
-    pub fn file_name(&self) -> String {
-        let image_name = if !self.graph_name.is_empty() {
+    /// A human readable description of this block for our error messages
+    pub fn description(&self) -> String {
+        let graph_name = if !self.graph_name.is_empty() {
+            format!("graph `{}`", self.graph_name)
+        } else {
+            String::from("graph")
+        };
+
+        format!(
+            "{graph_name} (block {}) in chapter `{}`",
+            self.index + 1,
+            self.chapter_name
+        )
+    }
+
+    pub fn file_name_for(&self, format: &str) -> String {
+        let image_name = if let Some(id) = &self.id {
+            format!(
+                "{}_{}.generated",
+                normalize_id(&self.chapter_name),
+                normalize_id(id)
+            )
+        } else if !self.graph_name.is_empty() {
             format!(
                 "{}_{}_{}.generated",
                 normalize_id(&self.chapter_name),
@@ -287,12 +1167,111 @@ impl GraphvizBlock {
             )
         };
 
-        format!("{image_name}.svg")
+        format!("{image_name}.{}", format_extension(format))
+    }
+
+    /// The file our code is in along with the line before our code starts, for translating the
+    /// line numbers in our code into lines in that file
+    pub fn code_origin(&self, source_file: &str) -> (String, usize) {
+        match &self.source {
+            Some(source) => (source.display().to_string(), 0),
+            // our code starts on the line after the fence
+            None => (source_file.to_string(), self.line),
+        }
+    }
+
+    /// A short text alternative for our diagram, from our `alt` attribute, our name or the `label`
+    /// of our graph
+    pub fn alt_text(&self) -> Option<String> {
+        self.attributes
+            .alt
+            .clone()
+            .or_else(|| (!self.graph_name.is_empty()).then(|| self.graph_name.clone()))
+            .or_else(|| graph_label(&self.code))
+    }
+
+    /// How long Graphviz gets to render our diagram, from our `timeout` attribute or the config
+    pub fn timeout(&self, config: &GraphvizConfig) -> Option<Duration> {
+        self.attributes
+            .timeout
+            .map(Duration::from_secs)
+            .or_else(|| config.timeout())
+    }
+
+    /// A prefix for the ids in our inline SVG, unique within our chapter's page
+    pub fn svg_id_prefix(&self) -> String {
+        format!(
+            "graphviz_{}_{}_",
+            normalize_id(&self.chapter_name),
+            self.index
+        )
+    }
+
+    pub fn output_path_for(&self, format: &str) -> PathBuf {
+        self.chapter_path
+            .join(&self.output_dir)
+            .join(self.file_name_for(format))
+    }
+
+    /// The URL of our chapter's directory from our generated files, for rebasing relative links
+    pub fn chapter_url_from_output(&self) -> String {
+        let output_dir = self.chapter_path.join(&self.output_dir);
+
+        relative_to(&self.chapter_path, &output_dir)
+            .components()
+            .map(|component| format!("{}/", component.as_os_str().to_string_lossy()))
+            .collect()
     }
 
-    pub fn output_path(&self) -> PathBuf {
-        self.chapter_path.join(self.file_name())
+    /// The URL of a generated file, relative to our chapter
+    pub fn file_url_for(&self, format: &str) -> String {
+        self.output_dir
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .chain([self.file_name_for(format).into()])
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// The `label` of a graph itself, ignoring the labels of its nodes, edges and subgraphs
+fn graph_label(code: &str) -> Option<String> {
+    lazy_static! {
+        // a `label=` statement or a `graph [label=...]` attribute statement
+        static ref GRAPH_LABEL_RE: Regex = Regex::new(
+            r#"(?:^|[{;\n])\s*(?:graph\s*\[[^\]]*?\b)?label\s*=\s*(?:"((?:[^"\\]|\\.)*)"|(\w+))"#
+        )
+        .unwrap();
     }
+
+    GRAPH_LABEL_RE
+        .captures_iter(code)
+        .map(|captures| captures.get(1).or(captures.get(2)).unwrap())
+        .find(|label| {
+            // anything deeper than the graph's own braces belongs to a subgraph
+            let before = &code[..label.start()];
+            before.matches('{').count() <= before.matches('}').count() + 1
+        })
+        .map(|label| {
+            label
+                .as_str()
+                // Graphviz escapes for quotes and line breaks
+                .replace("\\\"", "\"")
+                .replace("\\n", " ")
+                .replace("\\l", " ")
+                .replace("\\r", " ")
+                .trim()
+                .to_string()
+        })
+        .filter(|label| !label.is_empty())
+}
+
+/// A short hash of a block's code, so that its file name only changes when its code does
+fn content_hash(code: &str) -> String {
+    Sha256::digest(code.as_bytes())[..4]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
 }
 
 fn normalize_id(content: &str) -> String {
@@ -323,11 +1302,12 @@ mod test {
 
     impl GraphvizRenderer for NoopRenderer {
         async fn render_graphviz<'a>(
+            &self,
             block: GraphvizBlock,
             _config: &GraphvizConfig,
         ) -> Result<Vec<Event<'a>>> {
-            let file_name = block.file_name();
-            let output_path = block.output_path();
+            let file_name = block.file_name_for(&block.formats[0]);
+            let output_path = block.output_path_for(&block.formats[0]);
             let GraphvizBlock {
                 graph_name, index, ..
             } = block;
@@ -445,36 +1425,555 @@ digraph Test {
         assert_eq!(chapter.content, expected);
     }
 
-    #[tokio::test]
-    async fn preserve_escaping() {
-        let chapter = new_chapter(
-            r"# Chapter
+    struct EngineRenderer;
 
-*asteriks*
-/*asteriks/*
-( \int x dx = \frac{x^2}{2} + C)
+    impl GraphvizRenderer for EngineRenderer {
+        async fn render_graphviz<'a>(
+            &self,
+            GraphvizBlock {
+                graph_name, engine, ..
+            }: GraphvizBlock,
+            _config: &GraphvizConfig,
+        ) -> Result<Vec<Event<'a>>> {
+            Ok(vec![Event::Text(format!("{engine}|{graph_name}").into())])
+        }
+    }
 
-```dot process Graph Name
+    async fn process_engine_chapter(content: &str, config: GraphvizConfig) -> Result<String> {
+        Graphviz::new(EngineRenderer, PathBuf::from("/"), config)
+            .process_chapter(new_chapter(content))
+            .await
+            .map(|chapter| chapter.content)
+    }
+
+    #[tokio::test]
+    async fn engine_info_string() {
+        let content = r#"# Chapter
+```neato process Graph Name
 digraph Test {
     a -> b
 }
 ```
-",
-        );
-
-        let expected = format!(
-            r#"# Chapter
-
-*asteriks*
-/*asteriks/*
-( \int x dx = \frac{{x^2}}{{2}} + C)
-
-{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.svg|"/./book/{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.svg"|Graph Name|0"#
-        );
+"#;
 
-        let chapter = process_chapter(chapter).await.unwrap();
+        let content = process_engine_chapter(content, GraphvizConfig::default())
+            .await
+            .unwrap();
 
-        assert_eq!(chapter.content, expected);
+        assert_eq!(content, "# Chapter\n\nneato|Graph Name");
+    }
+
+    #[tokio::test]
+    async fn engine_attribute() {
+        let content = r#"# Chapter
+```dot process Graph engine=circo Name
+digraph Test {
+    a -> b
+}
+```
+"#;
+
+        let content = process_engine_chapter(content, GraphvizConfig::default())
+            .await
+            .unwrap();
+
+        assert_eq!(content, "# Chapter\n\ncirco|Graph Name");
+    }
+
+    #[tokio::test]
+    async fn quoted_name_attribute() {
+        let content = r#"# Chapter
+```dot process name="Data flow" engine=neato
+digraph Test {
+    a -> b
+}
+```
+"#;
+
+        let content = process_engine_chapter(content, GraphvizConfig::default())
+            .await
+            .unwrap();
+
+        assert_eq!(content, "# Chapter\n\nneato|Data flow");
+    }
+
+    #[tokio::test]
+    async fn engine_config_default() {
+        let content = r#"# Chapter
+```dot process
+digraph Test {
+    a -> b
+}
+```
+"#;
+
+        let config = GraphvizConfig {
+            engine: "sfdp".to_string(),
+            ..GraphvizConfig::default()
+        };
+        let content = process_engine_chapter(content, config).await.unwrap();
+
+        assert_eq!(content, "# Chapter\n\nsfdp|");
+    }
+
+    #[tokio::test]
+    async fn unknown_engine() {
+        let content = r#"# Chapter
+```dot process engine=nonsense
+digraph Test {
+    a -> b
+}
+```
+"#;
+
+        let result = process_engine_chapter(content, GraphvizConfig::default()).await;
+
+        assert!(result.is_err(), "Expected an error got {result:?}");
+    }
+
+    #[tokio::test]
+    async fn invalid_block_on_error() {
+        let invalid_block = r#"# Chapter
+
+````dot process engine=neatoo
+digraph Test {
+    a -> b
+}
+````"#;
+        let content = format!("{invalid_block}\n\n```dot process Other\ndigraph Other {{}}\n```\n");
+
+        let config = GraphvizConfig {
+            on_error: OnError::Warn,
+            ..GraphvizConfig::default()
+        };
+        let chapter = process_chapter_with_config(new_chapter(&content), config)
+            .await
+            .unwrap();
+        // the invalid block is left as is, and the next one is still rendered
+        assert_eq!(
+            chapter.content,
+            format!(
+                "{invalid_block}\n\n{NORMALIZED_CHAPTER_NAME}_other_1.generated.svg|\"/./book/{NORMALIZED_CHAPTER_NAME}_other_1.generated.svg\"|Other|1"
+            )
+        );
+
+        let config = GraphvizConfig {
+            on_error: OnError::Inline,
+            ..GraphvizConfig::default()
+        };
+        let chapter = process_chapter_with_config(new_chapter(&content), config)
+            .await
+            .unwrap();
+        assert!(
+            chapter
+                .content
+                .contains("Unknown Graphviz layout engine `neatoo`"),
+            "{}",
+            chapter.content
+        );
+    }
+
+    struct FailingRenderer;
+
+    impl GraphvizRenderer for FailingRenderer {
+        async fn render_graphviz<'a>(
+            &self,
+            _block: GraphvizBlock,
+            _config: &GraphvizConfig,
+        ) -> Result<Vec<Event<'a>>> {
+            Err(GraphvizError {
+                stderr: "Error: <stdin>: syntax error in line 2 near '->'".into(),
+            }
+            .into())
+        }
+    }
+
+    #[tokio::test]
+    async fn render_error_location() {
+        let chapter = new_chapter(
+            r#"# Chapter
+
+```dot process Graph Name
+digraph Test {
+    a -> ->
+}
+```
+"#,
+        );
+
+        let error = Graphviz::new(
+            FailingRenderer,
+            PathBuf::from("/"),
+            GraphvizConfig::default(),
+        )
+        .process_chapter(chapter)
+        .await
+        .expect_err("Expected rendering to fail");
+
+        assert_eq!(
+            error.to_string(),
+            "Failed to render graph `Graph Name` (block 1) in chapter `Test Chapter` at \
+             ./book/chapter.md:3, Graphviz reported a syntax error at ./book/chapter.md:5"
+        );
+        assert!(error.downcast_ref::<GraphvizError>().is_some());
+    }
+
+    #[tokio::test]
+    async fn on_error_warn() {
+        let expected = r#"# Chapter
+
+````dot process Graph Name
+digraph Test {
+    a -> ->
+}
+````"#;
+
+        let config = GraphvizConfig {
+            on_error: OnError::Warn,
+            ..GraphvizConfig::default()
+        };
+        let chapter = Graphviz::new(FailingRenderer, PathBuf::from("/"), config)
+            .process_chapter(new_chapter(expected))
+            .await
+            .unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[tokio::test]
+    async fn on_error_inline() {
+        let chapter = new_chapter(
+            r#"# Chapter
+
+```dot process Graph Name
+digraph Test {
+    a -> ->
+}
+```
+"#,
+        );
+
+        let config = GraphvizConfig {
+            on_error: OnError::Inline,
+            ..GraphvizConfig::default()
+        };
+        let chapter = Graphviz::new(FailingRenderer, PathBuf::from("/"), config)
+            .process_chapter(chapter)
+            .await
+            .unwrap();
+
+        assert!(
+            chapter
+                .content
+                .starts_with("# Chapter\n\n<div class=\"mdbook-graphviz-error\""),
+            "{}",
+            chapter.content
+        );
+        assert!(
+            chapter
+                .content
+                .contains("<pre>Error: &lt;stdin&gt;: syntax error in line 2 near '-&gt;'</pre>"),
+            "{}",
+            chapter.content
+        );
+        assert!(
+            chapter
+                .content
+                .contains("<pre><code>digraph Test {&#10;    a -&gt; -&gt;&#10;}</code></pre>"),
+            "{}",
+            chapter.content
+        );
+    }
+
+    #[tokio::test]
+    async fn format_attribute() {
+        let chapter = new_chapter(
+            r#"# Chapter
+```dot process Graph Name format=png,pdf
+digraph Test {
+    a -> b
+}
+```
+"#,
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.png|"/./book/{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.png"|Graph Name|0"#
+        );
+
+        let chapter = process_chapter(chapter).await.unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    struct UrlRenderer;
+
+    impl GraphvizRenderer for UrlRenderer {
+        async fn render_graphviz<'a>(
+            &self,
+            block: GraphvizBlock,
+            _config: &GraphvizConfig,
+        ) -> Result<Vec<Event<'a>>> {
+            let format = &block.formats[0];
+
+            Ok(vec![Event::Text(
+                format!(
+                    "{}|{:?}",
+                    block.file_url_for(format),
+                    block.output_path_for(format)
+                )
+                .into(),
+            )])
+        }
+    }
+
+    #[tokio::test]
+    async fn output_dir() {
+        let chapter = new_chapter(
+            r#"# Chapter
+```dot process Graph Name
+digraph Test {
+    a -> b
+}
+```
+"#,
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+../graphviz-out/book/{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.svg|"/./book/../graphviz-out/book/{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.svg""#
+        );
+
+        let config = GraphvizConfig {
+            output_dir: Some(PathBuf::from("graphviz-out")),
+            ..GraphvizConfig::default()
+        };
+        let chapter = Graphviz::new(UrlRenderer, PathBuf::from("/"), config)
+            .process_chapter(chapter)
+            .await
+            .unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn output_dir_gitignore() {
+        let output_dir = PathBuf::from("test-output/graphviz-out-ignored");
+        let _ = std::fs::remove_dir_all(&output_dir);
+
+        let gitignore = ignore_output_dir(&output_dir).unwrap();
+        assert_eq!(gitignore, output_dir.join(".gitignore"));
+        assert_eq!(
+            std::fs::read_to_string(&gitignore).unwrap(),
+            OUTPUT_DIR_GITIGNORE
+        );
+
+        // an unchanged file is left alone
+        let modified = std::fs::metadata(&gitignore).unwrap().modified().unwrap();
+        ignore_output_dir(&output_dir).unwrap();
+        assert_eq!(
+            std::fs::metadata(&gitignore).unwrap().modified().unwrap(),
+            modified
+        );
+    }
+
+    #[test]
+    fn book_assets() {
+        let root = PathBuf::from("test-output/book-assets");
+        let _ = std::fs::remove_dir_all(&root);
+
+        let scripts = write_book_asset(
+            &root,
+            &[
+                PathBuf::from("theme/other.js"),
+                PathBuf::from("js/mdbook-graphviz.js"),
+            ],
+            &CLIENT_JS_ASSET,
+        )
+        .unwrap();
+        assert_eq!(scripts, vec![root.join("js/mdbook-graphviz.js")]);
+        assert_eq!(std::fs::read_to_string(&scripts[0]).unwrap(), CLIENT_JS);
+        assert!(!root.join("theme/other.js").exists());
+
+        let stylesheets = write_book_asset(
+            &root,
+            &[PathBuf::from("mdbook-graphviz.css")],
+            &THEME_CSS_ASSET,
+        )
+        .unwrap();
+        assert_eq!(stylesheets, vec![root.join("mdbook-graphviz.css")]);
+        assert_eq!(std::fs::read_to_string(&stylesheets[0]).unwrap(), THEME_CSS);
+
+        // we only warn when the book doesn't load our asset
+        assert!(
+            write_book_asset(&root, &[], &THEME_CSS_ASSET)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn id_attribute() {
+        let chapter = new_chapter(
+            r#"# Chapter
+```dot process Graph Name id=Architecture
+digraph Test {
+    a -> b
+}
+```
+"#,
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+{NORMALIZED_CHAPTER_NAME}_architecture.generated.svg|"/./book/{NORMALIZED_CHAPTER_NAME}_architecture.generated.svg"|Graph Name|0"#
+        );
+
+        let chapter = process_chapter(chapter).await.unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[tokio::test]
+    async fn hash_file_names() {
+        let block = r#"```dot process Graph Name
+digraph Test {
+    a -> b
+}
+```
+"#;
+        let config = || GraphvizConfig {
+            file_names: FileNames::Hash,
+            ..GraphvizConfig::default()
+        };
+
+        let alone =
+            process_chapter_with_config(new_chapter(format!("# Chapter\n{block}")), config())
+                .await
+                .unwrap();
+        let inserted_before = process_chapter_with_config(
+            new_chapter(format!(
+                "# Chapter\n```dot process\ndigraph {{ c -> d }}\n```\n\n{block}"
+            )),
+            config(),
+        )
+        .await
+        .unwrap();
+
+        let file_name = alone
+            .content
+            .lines()
+            .last()
+            .unwrap()
+            .split('|')
+            .next()
+            .unwrap();
+        assert!(
+            file_name.starts_with(&format!("{NORMALIZED_CHAPTER_NAME}_graph_name_")),
+            "{file_name}"
+        );
+        assert!(inserted_before.content.contains(file_name));
+    }
+
+    struct FileRenderer;
+
+    impl GraphvizRenderer for FileRenderer {
+        async fn render_graphviz<'a>(
+            &self,
+            _block: GraphvizBlock,
+            _config: &GraphvizConfig,
+        ) -> Result<Vec<Event<'a>>> {
+            Ok(vec![])
+        }
+
+        fn output_files(&self, block: &GraphvizBlock) -> Vec<PathBuf> {
+            vec![block.output_path_for(&block.formats[0])]
+        }
+    }
+
+    #[tokio::test]
+    async fn file_name_collision() {
+        let chapter = new_chapter(
+            r#"# Chapter
+```dot process id=same
+digraph Test {
+    a -> b
+}
+```
+
+```dot process Other id=same
+digraph Test {
+    b -> c
+}
+```
+"#,
+        );
+
+        let error = Graphviz::new(FileRenderer, PathBuf::from("/"), GraphvizConfig::default())
+            .process_chapter(chapter)
+            .await
+            .expect_err("Expected our file names to collide");
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "graph `Other` (block 2) in chapter `Test Chapter` would overwrite \
+                 `/./book/{NORMALIZED_CHAPTER_NAME}_same.generated.svg` which is generated by \
+                 graph (block 1) in chapter `Test Chapter`, give one of them a unique `id` attribute"
+            )
+        );
+
+        let config = GraphvizConfig {
+            on_error: OnError::Warn,
+            ..GraphvizConfig::default()
+        };
+        let chapter = Graphviz::new(FileRenderer, PathBuf::from("/"), config)
+            .process_chapter(new_chapter(
+                "```dot process id=same\ndigraph {}\n```\n\n```dot process id=same\ndigraph {}\n```\n",
+            ))
+            .await
+            .unwrap();
+        // only the second block is left as is
+        assert_eq!(
+            chapter.content.trim_start(),
+            "````dot process id=same\ndigraph {}\n````"
+        );
+    }
+
+    #[tokio::test]
+    async fn preserve_escaping() {
+        let chapter = new_chapter(
+            r"# Chapter
+
+*asteriks*
+/*asteriks/*
+( \int x dx = \frac{x^2}{2} + C)
+
+```dot process Graph Name
+digraph Test {
+    a -> b
+}
+```
+",
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+*asteriks*
+/*asteriks/*
+( \int x dx = \frac{{x^2}}{{2}} + C)
+
+{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.svg|"/./book/{NORMALIZED_CHAPTER_NAME}_graph_name_0.generated.svg"|Graph Name|0"#
+        );
+
+        let chapter = process_chapter(chapter).await.unwrap();
+
+        assert_eq!(chapter.content, expected);
     }
 
     #[tokio::test]
@@ -517,6 +2016,7 @@ digraph Test {
     struct SleepyRenderer;
     impl GraphvizRenderer for SleepyRenderer {
         async fn render_graphviz<'a>(
+            &self,
             _block: GraphvizBlock,
             _config: &GraphvizConfig,
         ) -> Result<Vec<Event<'a>>> {
@@ -543,10 +2043,14 @@ digraph Test {
         }
 
         let start = Instant::now();
-        Graphviz::<SleepyRenderer>::new(PathBuf::from("/"), GraphvizConfig::default())
-            .process_sub_items(&mut chapters)
-            .await
-            .unwrap();
+        Graphviz::new(
+            SleepyRenderer,
+            PathBuf::from("/"),
+            GraphvizConfig::default(),
+        )
+        .process_sub_items(&mut chapters)
+        .await
+        .unwrap();
         let duration = start.elapsed();
 
         for item in chapters {
@@ -564,6 +2068,30 @@ digraph Test {
         );
     }
 
+    #[tokio::test]
+    async fn process_book_on_any_thread() {
+        let graphviz = Graphviz::new(NoopRenderer, PathBuf::from("/"), GraphvizConfig::default());
+        let mut book = Book::new_with_items(vec![BookItem::Chapter(new_chapter(
+            "```dot process Graph Name\ndigraph Test {}\n```\n",
+        ))]);
+
+        // spawning onto another thread requires our future to be `Send`
+        let book = tokio::spawn(async move {
+            graphviz.process_book(&mut book).await?;
+            Ok::<_, Error>(book)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        let chapter = book.chapters().next().unwrap();
+        assert!(
+            chapter.content.ends_with("|Graph Name|0"),
+            "{}",
+            chapter.content
+        );
+    }
+
     /// Test that we correctly process Chapter sub-items
     #[tokio::test]
     async fn chapter_sub_items() {
@@ -610,7 +2138,7 @@ digraph Test {
             )),
         ];
 
-        Graphviz::<NoopRenderer>::new(PathBuf::from("/"), GraphvizConfig::default())
+        Graphviz::new(NoopRenderer, PathBuf::from("/"), GraphvizConfig::default())
             .process_sub_items(&mut book_items)
             .await
             .unwrap();
@@ -628,6 +2156,411 @@ digraph Test {
         )
     }
 
+    #[test]
+    fn missing_chapter_links() {
+        let graphviz = Graphviz::new(NoopRenderer, PathBuf::from("/"), GraphvizConfig::default())
+            .with_chapters([
+                PathBuf::from("book/chapter.md"),
+                PathBuf::from("design/storage.md"),
+            ]);
+        let mut builder = graphviz.block_builder(None, "", "chapter.md", 3).unwrap();
+        builder.append_code(
+            r#"digraph Test {
+    a [URL="../design/storage.md#layout"]
+    b [href="../design/missing.md"]
+    a -> b [labelURL=chapter.md, tailhref="https://example.com/other.md"]
+    c [URL="gone.md"]
+}"#,
+        );
+        let block = builder.build(0, FileNames::Index);
+
+        assert_eq!(
+            graphviz.missing_chapter_links(&block, Path::new("./book/chapter.md")),
+            vec![
+                (String::from("../design/missing.md"), 3),
+                (String::from("gone.md"), 5)
+            ]
+        );
+    }
+
+    struct ParagraphRenderer;
+
+    impl GraphvizRenderer for ParagraphRenderer {
+        async fn render_graphviz<'a>(
+            &self,
+            block: GraphvizBlock,
+            _config: &GraphvizConfig,
+        ) -> Result<Vec<Event<'a>>> {
+            Ok(vec![
+                Event::Start(Tag::Paragraph),
+                Event::Text(block.anchor.into()),
+                Event::End(TagEnd::Paragraph),
+            ])
+        }
+    }
+
+    fn figures_book() -> Book {
+        let mut storage = Chapter::new(
+            "Storage",
+            String::from(
+                r#"# Storage
+
+```dot process Data Flow
+digraph { a -> b }
+```
+
+```dot process id=layout
+digraph { c -> d }
+```
+"#,
+            ),
+            PathBuf::from("design/storage.md"),
+            vec![],
+        );
+        storage.number = Some(SectionNumber::new([3]));
+        let intro = Chapter::new(
+            "Intro",
+            String::from(
+                r#"See {{#graphviz-ref Data Flow}} and {{#graphviz-ref layout}}, but not \{{#graphviz-ref layout}} or {{#graphviz-ref missing}}."#,
+            ),
+            PathBuf::from("guide/intro.md"),
+            vec![],
+        );
+
+        Book::new_with_items(vec![BookItem::Chapter(storage), BookItem::Chapter(intro)])
+    }
+
+    #[tokio::test]
+    async fn references() {
+        let book = figures_book();
+        let graphviz = Graphviz::new(
+            EngineRenderer,
+            PathBuf::from("/"),
+            GraphvizConfig::default(),
+        )
+        .with_book(&book);
+
+        let BookItem::Chapter(intro) = book.items[1].clone() else {
+            unreachable!()
+        };
+        let intro = graphviz.process_chapter(intro).await.unwrap();
+
+        assert_eq!(
+            intro.content,
+            r"See [Data Flow](../design/storage.md#graphviz-data-flow) and [layout](../design/storage.md#graphviz-layout), but not {{#graphviz-ref layout}} or {{#graphviz-ref missing}}."
+        );
+    }
+
+    #[tokio::test]
+    async fn figure_numbers() {
+        let book = figures_book();
+        let config = GraphvizConfig {
+            figure_numbers: true,
+            ..GraphvizConfig::default()
+        };
+        let graphviz =
+            Graphviz::new(ParagraphRenderer, PathBuf::from("/"), config).with_book(&book);
+
+        let BookItem::Chapter(intro) = book.items[1].clone() else {
+            unreachable!()
+        };
+        let intro = graphviz.process_chapter(intro).await.unwrap();
+
+        assert_eq!(
+            intro.content,
+            r"See [Figure 3.1](../design/storage.md#graphviz-data-flow) and [Figure 3.2](../design/storage.md#graphviz-layout), but not {{#graphviz-ref layout}} or {{#graphviz-ref missing}}."
+        );
+
+        let BookItem::Chapter(storage) = book.items[0].clone() else {
+            unreachable!()
+        };
+        let storage = graphviz.process_chapter(storage).await.unwrap();
+
+        assert_eq!(
+            storage.content,
+            r#"# Storage
+
+graphviz-data-flow
+
+<p class="mdbook-graphviz-caption">Figure 3.1: Data Flow</p>
+
+graphviz-layout
+
+<p class="mdbook-graphviz-caption">Figure 3.2</p>
+"#
+        );
+    }
+
+    #[tokio::test]
+    async fn figures() {
+        let chapter = new_chapter(
+            r#"# Chapter
+
+```dot process Data <Flow>
+digraph { a -> b }
+```
+
+```dot process Layout caption="The *storage* [layout](storage.md)"
+digraph { c -> d }
+```
+
+```dot process
+digraph { e -> f }
+```
+"#,
+        );
+        let config = GraphvizConfig {
+            figures: true,
+            ..GraphvizConfig::default()
+        };
+        let chapter = Graphviz::new(ParagraphRenderer, PathBuf::from("/"), config)
+            .process_chapter(chapter)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chapter.content,
+            r#"# Chapter
+
+<figure class="mdbook-graphviz-figure">
+
+graphviz-data-flow
+
+<figcaption>Data &lt;Flow&gt;</figcaption>
+</figure>
+
+<figure class="mdbook-graphviz-figure">
+
+graphviz-layout
+
+<figcaption>The <em>storage</em> <a href="storage.md">layout</a></figcaption>
+</figure>
+
+<figure class="mdbook-graphviz-figure">
+
+graphviz-test-chapter-3
+
+</figure>
+"#
+        );
+    }
+
+    #[tokio::test]
+    async fn caption_attribute() {
+        let chapter = new_chapter(
+            r#"```dot process Graph Name caption="A **bold** caption"
+digraph { a -> b }
+```
+
+```dot process Graph Name
+digraph { c -> d }
+```
+
+```dot process id=graph-name-2
+digraph { e -> f }
+```
+
+See {{#graphviz-ref graph-name-2}}
+"#,
+        );
+        let book = Book::new_with_items(vec![BookItem::Chapter(chapter.clone())]);
+        let chapter = Graphviz::new(
+            ParagraphRenderer,
+            PathBuf::from("/"),
+            GraphvizConfig::default(),
+        )
+        .with_book(&book)
+        .process_chapter(chapter)
+        .await
+        .unwrap();
+
+        // every anchor is unique within the page, including the ones references link to
+        assert_eq!(
+            chapter.content,
+            r#"graphviz-graph-name
+
+<p class="mdbook-graphviz-caption">A <strong>bold</strong> caption</p>
+
+graphviz-graph-name-2
+
+graphviz-graph-name-2-2
+
+See [graph-name-2](#graphviz-graph-name-2-2)"#
+        );
+    }
+
+    #[test]
+    fn graph_labels() {
+        assert_eq!(
+            graph_label(r#"digraph { label="Data \"flow\""; a [label="node"] }"#),
+            Some(String::from("Data \"flow\""))
+        );
+        assert_eq!(
+            graph_label("digraph {\n    graph [fontsize=10 label=\"Top\\nLevel\"]\n    a -> b\n}"),
+            Some(String::from("Top Level"))
+        );
+        assert_eq!(
+            graph_label(r#"digraph { a [label="node"]; subgraph cluster_0 { label="Inner" } }"#),
+            None
+        );
+        assert_eq!(graph_label(r#"digraph { label="" }"#), None);
+    }
+
+    struct SourceRenderer;
+
+    impl GraphvizRenderer for SourceRenderer {
+        async fn render_graphviz<'a>(
+            &self,
+            block: GraphvizBlock,
+            _config: &GraphvizConfig,
+        ) -> Result<Vec<Event<'a>>> {
+            let (code_file, _) = block.code_origin("chapter.md");
+            Ok(vec![
+                Event::Start(Tag::Paragraph),
+                Event::Text(format!("{}|{code_file}|{}", block.line, block.code.trim()).into()),
+                Event::End(TagEnd::Paragraph),
+            ])
+        }
+    }
+
+    /// A book root with a `src/diagrams/flow.dot` to include
+    fn include_root() -> PathBuf {
+        let root = PathBuf::from("test-output/includes");
+        std::fs::create_dir_all(root.join("src/diagrams")).unwrap();
+        std::fs::write(root.join("src/diagrams/flow.dot"), "digraph { a -> b }\n").unwrap();
+
+        root
+    }
+
+    async fn process_include_chapter(content: &str) -> Result<String> {
+        process_include_chapter_with_config(content, GraphvizConfig::default()).await
+    }
+
+    async fn process_include_chapter_with_config(
+        content: &str,
+        config: GraphvizConfig,
+    ) -> Result<String> {
+        let root = include_root();
+        Graphviz::new(SourceRenderer, root.join("src"), config)
+            .with_root(root, vec![])
+            .process_chapter(Chapter::new(
+                CHAPTER_NAME,
+                content.to_string(),
+                PathBuf::from("guide/chapter.md"),
+                vec![],
+            ))
+            .await
+            .map(|chapter| chapter.content)
+    }
+
+    #[tokio::test]
+    async fn include_files() {
+        let content = process_include_chapter(
+            r#"# Chapter
+
+{{#graphviz ../diagrams/flow.dot name=Flow}}
+
+\{{#graphviz ../diagrams/flow.dot}}
+
+```dot process src="../diagrams/flow.dot"
+```
+
+```dot process
+digraph { inline }
+```
+"#,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            content,
+            r#"# Chapter
+
+3|diagrams/flow.dot|digraph { a -> b }
+
+{{#graphviz ../diagrams/flow.dot}}
+
+7|diagrams/flow.dot|digraph { a -> b }
+
+10|chapter.md|digraph { inline }"#
+        );
+    }
+
+    #[tokio::test]
+    async fn include_lines_with_references() {
+        let root = include_root();
+        let chapter = Chapter::new(
+            CHAPTER_NAME,
+            // links are longer than the references they replace, by more than the line we add
+            format!(
+                "{}\n\n{{{{#graphviz ../diagrams/flow.dot name=\"Storage Layout\"}}}}\n",
+                "{{#graphviz-ref Storage Layout}} ".repeat(10)
+            ),
+            PathBuf::from("guide/chapter.md"),
+            vec![],
+        );
+        let book = Book::new_with_items(vec![BookItem::Chapter(chapter.clone())]);
+
+        let content = Graphviz::new(SourceRenderer, root.join("src"), GraphvizConfig::default())
+            .with_root(root, vec![])
+            .with_book(&book)
+            .process_chapter(chapter)
+            .await
+            .unwrap()
+            .content;
+
+        assert!(
+            content.starts_with("[Storage Layout](#graphviz-storage-layout)"),
+            "{content}"
+        );
+        assert!(
+            content.ends_with("3|diagrams/flow.dot|digraph { a -> b }"),
+            "{content}"
+        );
+    }
+
+    #[tokio::test]
+    async fn include_errors() {
+        let error = process_include_chapter("{{#graphviz missing.dot}}")
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("Couldn't read"), "{error:#}");
+
+        let error = process_include_chapter("{{#graphviz ../../../outside.dot}}")
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{error:#}").contains("outside of the book's root directory"),
+            "{error:#}"
+        );
+
+        let error = process_include_chapter(
+            r#"```dot process src=../diagrams/flow.dot
+digraph { a -> b }
+```"#,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            format!("{error:#}").contains("can't have any code of its own"),
+            "{error:#}"
+        );
+
+        let config = GraphvizConfig {
+            on_error: OnError::Inline,
+            ..GraphvizConfig::default()
+        };
+        let content = process_include_chapter_with_config("{{#graphviz missing.dot}}", config)
+            .await
+            .unwrap();
+        assert!(
+            content.starts_with("<div class=\"mdbook-graphviz-error\"")
+                && content.contains("Couldn't read"),
+            "{content}"
+        );
+    }
+
     async fn process_chapter(chapter: Chapter) -> Result<Chapter> {
         process_chapter_with_config(chapter, GraphvizConfig::default()).await
     }
@@ -636,7 +2569,7 @@ digraph Test {
         chapter: Chapter,
         config: GraphvizConfig,
     ) -> Result<Chapter> {
-        Graphviz::<NoopRenderer>::new(PathBuf::from("/"), config)
+        Graphviz::new(NoopRenderer, PathBuf::from("/"), config)
             .process_chapter(chapter)
             .await
     }
@@ -1,142 +1,607 @@
+use std::future::Future;
 use std::io;
-use std::process::Stdio;
-use tokio::process::{Child, Command};
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{error, fmt};
+use tokio::fs;
+use tokio::process::Command;
 
 use mdbook_markdown::pulldown_cmark::{Event, LinkType, Tag, TagEnd};
-use mdbook_preprocessor::errors::Result;
+use mdbook_preprocessor::errors::{Error, Result};
 use regex::Regex;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
 
-use crate::preprocessor::{GraphvizBlock, GraphvizConfig};
+use crate::attributes::BlockAttributes;
+use crate::cache::{RenderCache, graphviz_version};
+use crate::config::{Backend, GraphvizConfig, SVG_FORMAT, format_extension};
+use crate::native::{NATIVE_VERSION, render_svg};
+use crate::preprocessor::GraphvizBlock;
+use crate::svg::{SvgOptions, has_links, rewrite_svg};
 
-pub trait GraphvizRenderer {
-    async fn render_graphviz<'a>(
+tokio::task_local! {
+    /// Limits how many Graphviz processes we run at once
+    static GRAPHVIZ_PERMITS: Arc<Semaphore>;
+}
+
+/// Run our future with at most `max_concurrency` Graphviz processes running at once
+pub async fn with_max_concurrency<F: Future>(max_concurrency: usize, future: F) -> F::Output {
+    GRAPHVIZ_PERMITS
+        .scope(Arc::new(Semaphore::new(max_concurrency)), future)
+        .await
+}
+
+/// Wait for our turn to run Graphviz, if we're limiting how many processes run at once
+async fn graphviz_permit() -> Result<Option<OwnedSemaphorePermit>> {
+    match GRAPHVIZ_PERMITS.try_with(Arc::clone) {
+        Ok(permits) => Ok(Some(permits.acquire_owned().await?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Graphviz exited unsuccessfully, along with what it reported on stderr
+#[derive(Debug)]
+pub struct GraphvizError {
+    pub stderr: String,
+}
+
+impl GraphvizError {
+    /// The line of our code that Graphviz reported a syntax error on
+    pub fn syntax_error_line(&self) -> Option<usize> {
+        lazy_static! {
+            static ref SYNTAX_ERROR_RE: Regex = Regex::new(r"syntax error in line (\d+)").unwrap();
+        }
+
+        SYNTAX_ERROR_RE
+            .captures(&self.stderr)
+            .and_then(|captures| captures[1].parse().ok())
+    }
+}
+
+impl fmt::Display for GraphvizError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error response from Graphviz")?;
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            write!(f, ": {stderr}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for GraphvizError {}
+
+/// Renders our blocks into the Markdown events that replace them in their chapter, implementations
+/// can use `async fn render_graphviz`
+pub trait GraphvizRenderer: Send + Sync {
+    fn render_graphviz<'a>(
+        &self,
         block: GraphvizBlock,
         config: &GraphvizConfig,
-    ) -> Result<Vec<Event<'a>>>;
+    ) -> impl Future<Output = Result<Vec<Event<'a>>>> + Send;
+
+    /// The files rendering this block will write, so that we can clean them up once they're stale
+    fn output_files(&self, _block: &GraphvizBlock) -> Vec<PathBuf> {
+        vec![]
+    }
 }
 
+/// Inlines SVGs rendered by the Graphviz CLI
 pub struct CLIGraphviz;
 
 impl GraphvizRenderer for CLIGraphviz {
     async fn render_graphviz<'a>(
-        GraphvizBlock { code, .. }: GraphvizBlock,
+        &self,
+        block: GraphvizBlock,
         config: &GraphvizConfig,
     ) -> Result<Vec<Event<'a>>> {
-        let output = call_graphviz(&config.arguments, &code)
-            .await?
-            .wait_with_output()
-            .await?;
-        if output.status.success() {
-            let graph_svg = String::from_utf8(output.stdout)?;
+        // we can only inline SVGs so ignore the configured formats
+        let output = render_output(
+            &block.engine,
+            SVG_FORMAT,
+            &block.code,
+            block.timeout(config),
+            config,
+        )
+        .await?;
+        let graph_svg = String::from_utf8(output)?;
 
-            Ok(vec![
-                Event::Html(format_output(graph_svg).into()),
-                Event::Text("\n\n".into()),
-            ])
-        } else {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "Error response from Graphviz").into())
-        }
+        Ok(vec![
+            Event::Html(format_output(graph_svg, &block, config)?.into()),
+            Event::Text("\n\n".into()),
+        ])
     }
 }
 
+/// Leaves the DOT source in the page for the reader's browser to render with viz.js
+pub struct ClientGraphviz;
+
+impl GraphvizRenderer for ClientGraphviz {
+    async fn render_graphviz<'a>(
+        &self,
+        block: GraphvizBlock,
+        config: &GraphvizConfig,
+    ) -> Result<Vec<Event<'a>>> {
+        Ok(vec![
+            Event::Html(format_client_output(&block, config).into()),
+            Event::Text("\n\n".into()),
+        ])
+    }
+}
+
+/// Writes files rendered by the Graphviz CLI next to each chapter and links to them
 pub struct CLIGraphvizToFile;
 
 impl GraphvizRenderer for CLIGraphvizToFile {
     async fn render_graphviz<'a>(
+        &self,
         block: GraphvizBlock,
         config: &GraphvizConfig,
     ) -> Result<Vec<Event<'a>>> {
-        let file_name = block.file_name();
-        let output_path = block.output_path();
+        let mut embed = false;
+        for format in &block.formats {
+            let mut output = render_output(
+                &block.engine,
+                format,
+                &block.code,
+                block.timeout(config),
+                config,
+            )
+            .await?;
+            if format_extension(format) == SVG_FORMAT
+                && has_links(&String::from_utf8_lossy(&output))
+            {
+                let link_base = block.chapter_url_from_output();
+                let svg = rewrite_svg(
+                    str::from_utf8(&output)?,
+                    &SvgOptions {
+                        link_base: Some(&link_base),
+                        label: block.alt_text().as_deref(),
+                        description: block.attributes.description.as_deref(),
+                        ..SvgOptions::default()
+                    },
+                )
+                .map_err(|e| e.context("Couldn't process the SVG from Graphviz"))?;
+                output = svg.into_bytes();
+                // an image can't be clicked through, so embed our SVG to keep its links working
+                embed |= format == &block.formats[0];
+            }
+
+            let output_path = block.output_path_for(format);
+            if let Some(output_dir) = output_path.parent() {
+                fs::create_dir_all(output_dir).await?;
+            }
+            fs::write(output_path, output).await?;
+        }
+
+        let file_url = block.file_url_for(&block.formats[0]);
+        // any extra formats are linked to after our image
+        let extra_file_urls = block.formats[1..]
+            .iter()
+            .map(|format| {
+                (
+                    format_extension(format).to_uppercase(),
+                    block.file_url_for(format),
+                )
+            })
+            .collect::<Vec<_>>();
+        let alt_text = block.alt_text().unwrap_or_default();
         let GraphvizBlock {
-            graph_name, code, ..
+            graph_name,
+            anchor,
+            attributes,
+            ..
         } = block;
 
-        let output_path_str = output_path
-            .to_str()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Couldn't build output path"))?;
-
-        let mut args_with_output = config.arguments.clone();
-        args_with_output.extend(["-o", output_path_str].iter().map(|s| s.to_string()));
-
-        if call_graphviz(&args_with_output, &code)
-            .await?
-            .wait()
-            .await?
-            .success()
-        {
-            let mut nodes = vec![];
-
-            if config.link_to_file {
-                let link_tag = Tag::Link {
-                    link_type: LinkType::Inline,
-                    dest_url: file_name.clone().into(),
-                    title: graph_name.clone().into(),
-                    id: "".into(),
-                };
-                nodes.push(Event::Start(link_tag));
-            }
+        // Markdown images can't have a class, width or id so fall back to HTML for those
+        let html_image = embed || attributes.class.is_some() || attributes.width.is_some();
+
+        let mut nodes = vec![];
+        if !html_image {
+            // give ourselves an anchor to link to instead
+            nodes.push(Event::InlineHtml(
+                format!("<a id=\"{}\"></a>", escape_html(&anchor)).into(),
+            ));
+        }
 
+        if config.link_to_file {
+            let link_tag = Tag::Link {
+                link_type: LinkType::Inline,
+                dest_url: file_url.clone().into(),
+                title: graph_name.clone().into(),
+                id: "".into(),
+            };
+            nodes.push(Event::Start(link_tag));
+        }
+
+        if embed {
+            nodes.push(Event::InlineHtml(
+                format_object(&file_url, &graph_name, &alt_text, &anchor, &attributes).into(),
+            ));
+        } else if html_image {
+            nodes.push(Event::InlineHtml(
+                format_image(&file_url, &graph_name, &alt_text, &anchor, &attributes).into(),
+            ));
+        } else {
             let image_tag = Tag::Image {
                 link_type: LinkType::Inline,
-                dest_url: file_name.into(),
+                dest_url: file_url.into(),
                 title: graph_name.into(),
                 id: "".into(),
             };
 
-            nodes.extend([Event::Start(image_tag), Event::End(TagEnd::Image)]);
+            nodes.extend([
+                Event::Start(image_tag),
+                Event::Text(alt_text.into()),
+                Event::End(TagEnd::Image),
+            ]);
+        }
+
+        if config.link_to_file {
+            nodes.push(Event::End(TagEnd::Link));
+        }
+
+        for (label, extra_file_url) in extra_file_urls {
+            let link_tag = Tag::Link {
+                link_type: LinkType::Inline,
+                dest_url: extra_file_url.into(),
+                title: "".into(),
+                id: "".into(),
+            };
+            nodes.extend([
+                Event::Text(" ".into()),
+                Event::Start(link_tag),
+                Event::Text(label.into()),
+                Event::End(TagEnd::Link),
+            ]);
+        }
+        nodes.push(Event::Text("\n\n".into()));
+
+        Ok(nodes)
+    }
+
+    fn output_files(&self, block: &GraphvizBlock) -> Vec<PathBuf> {
+        block
+            .formats
+            .iter()
+            .map(|format| block.output_path_for(format))
+            .collect()
+    }
+}
+
+/// Render our code with Graphviz, reusing the output of a previous run when we have it cached
+pub async fn render_output(
+    engine: &str,
+    format: &str,
+    code: &str,
+    timeout: Option<Duration>,
+    config: &GraphvizConfig,
+) -> Result<Vec<u8>> {
+    if let Some(max_input_size) = config.max_input_size
+        && code.len() > max_input_size
+    {
+        return Err(Error::msg(format!(
+            "The diagram is {} bytes, more than the {max_input_size} byte max-input-size",
+            code.len()
+        )));
+    }
+
+    let mut arguments = config.arguments.clone();
+    arguments.push(format!("-T{format}"));
+    let arguments = &arguments;
+
+    let Some(cache_dir) = &config.cache_dir else {
+        return call_backend(engine, format, arguments, code, timeout, config).await;
+    };
+
+    let version = match config.backend {
+        Backend::Cli => graphviz_version(&config.engine_command(engine), &config.env).await?,
+        Backend::Native => NATIVE_VERSION.to_string(),
+    };
+    let cache = RenderCache::new(cache_dir.clone());
+    let key = RenderCache::key(&version, engine, arguments, &config.env, code);
+    if let Some(output) = cache.get(&key).await {
+        return Ok(output);
+    }
+
+    let output = call_backend(engine, format, arguments, code, timeout, config).await?;
+    cache.put(&key, &output).await?;
+
+    Ok(output)
+}
+
+async fn call_backend(
+    engine: &str,
+    format: &str,
+    arguments: &[String],
+    code: &str,
+    timeout: Option<Duration>,
+    config: &GraphvizConfig,
+) -> Result<Vec<u8>> {
+    match config.backend {
+        // stops reading once Graphviz goes over max-output-size
+        Backend::Cli => call_graphviz(engine, arguments, code, timeout, config).await,
+        Backend::Native => {
+            // blocks can pick their own engine and formats
+            Backend::Native.validate(engine, &[format.to_string()])?;
+            let output = render_svg(code)?;
 
-            if config.link_to_file {
-                nodes.push(Event::End(TagEnd::Link));
+            if let Some(max_output_size) = config.max_output_size
+                && output.len() > max_output_size
+            {
+                return Err(Error::msg(format!(
+                    "layout-rs output {} bytes, more than the {max_output_size} byte \
+                     max-output-size",
+                    output.len()
+                )));
             }
-            nodes.push(Event::Text("\n\n".into()));
 
-            Ok(nodes)
-        } else {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "Error response from Graphviz").into())
+            Ok(output)
         }
     }
 }
 
-async fn call_graphviz(arguments: &Vec<String>, code: &str) -> Result<Child> {
-    let mut child = Command::new("dot")
+/// Explain how to fix not being able to start a Graphviz executable
+pub(crate) fn couldnt_run(command: &Path, error: io::Error) -> Error {
+    Error::new(error).context(format!(
+        "Couldn't run `{}`, make sure Graphviz is installed and on your PATH, set \
+         `graphviz-path` or use `backend = \"native\"`",
+        command.display()
+    ))
+}
+
+async fn call_graphviz(
+    engine: &str,
+    arguments: &[String],
+    code: &str,
+    timeout: Option<Duration>,
+    config: &GraphvizConfig,
+) -> Result<Vec<u8>> {
+    // held until Graphviz exits
+    let _permit = graphviz_permit().await?;
+
+    let command = config.engine_command(engine);
+    let mut child = Command::new(&command)
         .args(arguments)
+        .envs(&config.env)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+        .stderr(Stdio::piped())
+        // so that giving up on a slow or oversized render stops Graphviz too
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| couldnt_run(&command, e))?;
 
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin.write_all(code.as_bytes()).await?;
+    let (Some(mut stdin), Some(stdout), Some(mut stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        unreachable!("We piped all of Graphviz's standard streams");
+    };
+    let run = async {
+        let write_stdin = async {
+            match stdin.write_all(code.as_bytes()).await {
+                // Graphviz stops reading at its first error, which we'll get from stderr instead
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+                result => result?,
+            }
+            // closing stdin tells Graphviz it has the whole graph
+            drop(stdin);
+            Ok::<_, Error>(())
+        };
+        let read_stdout = async {
+            // reading one byte past the limit is enough to know Graphviz went over it
+            let limit = config
+                .max_output_size
+                .map_or(u64::MAX, |max_output_size| max_output_size as u64 + 1);
+            let mut output = vec![];
+            stdout.take(limit).read_to_end(&mut output).await?;
+
+            match config.max_output_size {
+                Some(max_output_size) if output.len() > max_output_size => {
+                    Err(Error::msg(format!(
+                        "`{}` output more than the {max_output_size} byte max-output-size and \
+                         was stopped",
+                        command.display()
+                    )))
+                }
+                _ => Ok(output),
+            }
+        };
+        let read_stderr = async {
+            let mut output = vec![];
+            stderr.read_to_end(&mut output).await?;
+            Ok::<_, Error>(output)
+        };
+        // all at once so that neither Graphviz nor we get stuck on a full pipe
+        let ((), stdout, stderr) = futures::try_join!(write_stdin, read_stdout, read_stderr)?;
+
+        Ok::<_, Error>(Output {
+            status: child.wait().await?,
+            stdout,
+            stderr,
+        })
+    };
+    let output = match timeout {
+        Some(timeout) => time::timeout(timeout, run).await.map_err(|_| {
+            Error::msg(format!(
+                "`{}` didn't finish within the {}s timeout and was stopped",
+                command.display(),
+                timeout.as_secs()
+            ))
+        })??,
+        None => run.await?,
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if output.status.success() {
+        // pass along any warnings from Graphviz
+        eprint!("{stderr}");
+
+        Ok(output.stdout)
+    } else {
+        Err(GraphvizError { stderr }.into())
     }
+}
+
+fn format_output(output: String, block: &GraphvizBlock, config: &GraphvizConfig) -> Result<String> {
+    let output = rewrite_svg(
+        &output,
+        &SvgOptions {
+            width: block.attributes.width,
+            // every diagram on a page shares one id namespace, so keep ours from colliding
+            id_prefix: &block.svg_id_prefix(),
+            theme_aware: config.theme_aware,
+            link_base: None,
+            label: block.alt_text().as_deref(),
+            description: block.attributes.description.as_deref(),
+        },
+    )
+    .map_err(|e| e.context("Couldn't process the SVG from Graphviz"))?;
 
-    Ok(child)
+    Ok(format!(
+        "<div class=\"{}\" id=\"{}\">{output}</div>",
+        output_classes(&block.attributes),
+        escape_html(&block.anchor)
+    ))
 }
 
-fn format_output(output: String) -> String {
-    lazy_static! {
-        static ref DOCTYPE_RE: Regex = Regex::new(r"<!DOCTYPE [^>]+>").unwrap();
-        static ref XML_TAG_RE: Regex = Regex::new(r"<\?xml [^>]+\?>").unwrap();
-        static ref NEW_LINE_TAGS_RE: Regex = Regex::new(r">\s+<").unwrap();
-        static ref NEWLINES_RE: Regex = Regex::new(r"\n").unwrap();
+/// A placeholder that our script replaces with the rendered diagram, newlines in the source are
+/// escaped so that a blank line doesn't end our HTML block
+fn format_client_output(block: &GraphvizBlock, config: &GraphvizConfig) -> String {
+    let mut output = format!(
+        "<div class=\"{} mdbook-graphviz-client\" id=\"{}\" data-engine=\"{}\" data-viz-url=\"{}\"",
+        output_classes(&block.attributes),
+        escape_html(&block.anchor),
+        escape_html(&block.engine),
+        // validation makes sure we have one when rendering on the client
+        escape_html(config.viz_url.as_deref().unwrap_or_default())
+    );
+    if let Some(width) = block.attributes.width {
+        output.push_str(&format!(" data-width=\"{width}\""));
+    }
+    if let Some(alt_text) = block.alt_text() {
+        output.push_str(&format!(
+            " role=\"img\" aria-label=\"{}\"",
+            escape_html(&alt_text)
+        ));
     }
+    output.push_str(&format!(
+        "><pre class=\"mdbook-graphviz-source\" hidden>{}</pre></div>",
+        escape_html(&block.code)
+    ));
 
-    // yes yes: https://stackoverflow.com/a/1732454 ZA̡͊͠͝LGΌ and such
-    let output = DOCTYPE_RE.replace(&output, "");
-    let output = XML_TAG_RE.replace(&output, "");
-    // remove newlines between our tags to help commonmark determine the full set of HTML
-    let output = NEW_LINE_TAGS_RE.replace_all(&output, "><");
-    // remove explicit newlines as they won't be preserved and break commonmark parsing
-    let output = NEWLINES_RE.replace_all(&output, "");
-    let output = output.trim();
+    output
+}
+
+/// An HTML image, for when Markdown images aren't enough
+fn format_image(
+    file_url: &str,
+    graph_name: &str,
+    alt_text: &str,
+    anchor: &str,
+    attributes: &BlockAttributes,
+) -> String {
+    let mut image = format!(
+        "<img src=\"{}\" alt=\"{}\" title=\"{}\" class=\"{}\" id=\"{}\"",
+        escape_html(file_url),
+        escape_html(alt_text),
+        escape_html(graph_name),
+        output_classes(attributes),
+        escape_html(anchor)
+    );
+    if let Some(width) = attributes.width {
+        image.push_str(&format!(" width=\"{width}\""));
+    }
+    image.push('>');
+
+    image
+}
+
+/// An embedded SVG, for diagrams with links
+fn format_object(
+    file_url: &str,
+    graph_name: &str,
+    alt_text: &str,
+    anchor: &str,
+    attributes: &BlockAttributes,
+) -> String {
+    let mut object = format!(
+        "<object data=\"{}\" type=\"image/svg+xml\" title=\"{}\" role=\"img\" aria-label=\"{}\" class=\"{}\" id=\"{}\"",
+        escape_html(file_url),
+        escape_html(graph_name),
+        escape_html(alt_text),
+        output_classes(attributes),
+        escape_html(anchor)
+    );
+    if let Some(width) = attributes.width {
+        object.push_str(&format!(" width=\"{width}\""));
+    }
+    object.push_str("></object>");
+
+    object
+}
+
+fn output_classes(attributes: &BlockAttributes) -> String {
+    match &attributes.class {
+        Some(class) => format!("mdbook-graphviz-output {}", escape_html(class)),
+        None => String::from("mdbook-graphviz-output"),
+    }
+}
+
+/// An HTML panel describing why a block failed to render, along with its original source
+pub fn format_error(error: &Error, code: &str) -> String {
+    let details = match error.downcast_ref::<GraphvizError>() {
+        Some(GraphvizError { stderr }) => stderr.trim().to_string(),
+        None => error
+            .chain()
+            .skip(1)
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>()
+            .join(": "),
+    };
+
+    let mut output = String::from(
+        "<div class=\"mdbook-graphviz-error\" style=\"border: 1px solid #d33; border-radius: 4px; padding: 0 1em;\">",
+    );
+    output.push_str(&format!(
+        "<p><strong>{}</strong></p>",
+        escape_html(&error.to_string())
+    ));
+    if !details.is_empty() {
+        output.push_str(&format!("<pre>{}</pre>", escape_html(&details)));
+    }
+    output.push_str(&format!("<pre><code>{}</code></pre>", escape_html(code)));
+    output.push_str("</div>");
 
-    format!("<div class=\"mdbook-graphviz-output\">{output}</div>")
+    output
+}
+
+/// Escape text for HTML, newlines are escaped too so that blank lines can't end our HTML block
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            _ => escaped.push(ch),
+        }
+    }
+
+    escaped
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::preprocessor::new_runtime;
+    use std::collections::BTreeMap;
 
     #[tokio::test]
     async fn inline_events() {
@@ -144,14 +609,23 @@ mod test {
 
         let block = GraphvizBlock {
             graph_name: "Name".into(),
+            id: None,
+            anchor: "graphviz-name".into(),
+            engine: "dot".into(),
+            formats: vec![SVG_FORMAT.into()],
+            attributes: BlockAttributes::default(),
             code: code.into(),
             chapter_name: "".into(),
             chapter_path: "".into(),
+            output_dir: "".into(),
             index: 0,
+            line: 1,
+            source: None,
         };
 
         let config = GraphvizConfig::default();
-        let mut events = CLIGraphviz::render_graphviz(block, &config)
+        let mut events = CLIGraphviz
+            .render_graphviz(block, &config)
             .await
             .unwrap()
             .into_iter();
@@ -163,28 +637,224 @@ mod test {
         assert_eq!(events.next(), None);
     }
 
+    #[tokio::test]
+    async fn max_concurrency() {
+        assert!(graphviz_permit().await.unwrap().is_none());
+
+        with_max_concurrency(2, async {
+            let first = graphviz_permit().await.unwrap();
+            let _second = graphviz_permit().await.unwrap();
+            assert_eq!(
+                GRAPHVIZ_PERMITS.with(|permits| permits.available_permits()),
+                0
+            );
+
+            drop(first);
+            assert_eq!(
+                GRAPHVIZ_PERMITS.with(|permits| permits.available_permits()),
+                1
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn client_events() {
+        let mut block = output_block(BlockAttributes::default());
+        block.code = "digraph Test {\n    a -> \"<b>\"\n\n}".into();
+
+        let config = GraphvizConfig {
+            viz_url: Some(String::from("js/viz-standalone.js")),
+            ..GraphvizConfig::default()
+        };
+        let events = ClientGraphviz
+            .render_graphviz(block, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            events,
+            vec![
+                Event::Html(
+                    concat!(
+                        r#"<div class="mdbook-graphviz-output mdbook-graphviz-client" id="graphviz-name" data-engine="dot" "#,
+                        r#"data-viz-url="js/viz-standalone.js" role="img" aria-label="Name">"#,
+                        r#"<pre class="mdbook-graphviz-source" hidden>digraph Test {&#10;    a -&gt; &quot;&lt;b&gt;&quot;&#10;&#10;}</pre></div>"#
+                    )
+                    .into()
+                ),
+                Event::Text("\n\n".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn syntax_error() {
+        let mut block = output_block(BlockAttributes::default());
+        block.code = "digraph Test {\n    a -> -> SYNTAXERROR\n}".into();
+
+        let config = GraphvizConfig::default();
+        let error = CLIGraphviz
+            .render_graphviz(block, &config)
+            .await
+            .expect_err("Expect rendering to fail");
+        let error = error
+            .downcast_ref::<GraphvizError>()
+            .expect("Expected a GraphvizError");
+        assert_eq!(error.syntax_error_line(), Some(2), "{error}");
+    }
+
+    #[tokio::test]
+    async fn syntax_error_with_large_input() {
+        // more than fits in a pipe, from something that gives up without reading it like Graphviz
+        let code = "digraph { a -> b }\n".repeat(50_000);
+        let error = call_graphviz(
+            "sh",
+            &[
+                "-c".into(),
+                "echo 'syntax error in line 1' >&2; exit 1".into(),
+            ],
+            &code,
+            None,
+            &GraphvizConfig::default(),
+        )
+        .await
+        .expect_err("Expect the command to fail");
+
+        let error = error
+            .downcast_ref::<GraphvizError>()
+            .expect("Expected a GraphvizError");
+        assert_eq!(error.stderr, "syntax error in line 1\n");
+    }
+
+    #[test]
+    fn timeout() {
+        let started = std::time::Instant::now();
+        // on the runtime we process books with, which needs timers for this
+        let error = new_runtime()
+            .block_on(call_graphviz(
+                // any slow command will do, we just need it to outlast our timeout
+                "sleep",
+                &["10".into()],
+                "",
+                Some(Duration::from_secs(1)),
+                &GraphvizConfig::default(),
+            ))
+            .expect_err("Expect the command to time out");
+
+        assert_eq!(
+            error.to_string(),
+            "`sleep` didn't finish within the 1s timeout and was stopped"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn graphviz_command() {
+        let config = GraphvizConfig {
+            graphviz_path: Some(PathBuf::from("test-output/missing-graphviz")),
+            ..GraphvizConfig::default()
+        };
+        let error = call_graphviz("neato", &[], "", None, &config)
+            .await
+            .expect_err("Expect Graphviz to be missing");
+        assert!(
+            error
+                .to_string()
+                .starts_with("Couldn't run `test-output/missing-graphviz/neato`"),
+            "{error}"
+        );
+
+        // any command will do, we just need to see the environment it runs with
+        let config = GraphvizConfig {
+            engine_paths: BTreeMap::from([(String::from("dot"), PathBuf::from("printenv"))]),
+            env: BTreeMap::from([(String::from("GDFONTPATH"), String::from("fonts"))]),
+            ..config
+        };
+        let output = call_graphviz("dot", &["GDFONTPATH".into()], "", None, &config)
+            .await
+            .expect("Expect the command to succeed");
+        assert_eq!(output, b"fonts\n");
+    }
+
+    #[tokio::test]
+    async fn size_limits() {
+        let code = "digraph Test { a -> b }";
+
+        let config = GraphvizConfig {
+            max_input_size: Some(10),
+            ..GraphvizConfig::default()
+        };
+        let error = render_output("dot", SVG_FORMAT, code, None, &config)
+            .await
+            .expect_err("Expect the input to be too large");
+        assert_eq!(
+            error.to_string(),
+            "The diagram is 23 bytes, more than the 10 byte max-input-size"
+        );
+
+        let config = GraphvizConfig {
+            max_output_size: Some(10),
+            ..GraphvizConfig::default()
+        };
+        let error = render_output("dot", SVG_FORMAT, code, None, &config)
+            .await
+            .expect_err("Expect the output to be too large");
+        assert_eq!(
+            error.to_string(),
+            "`dot` output more than the 10 byte max-output-size and was stopped"
+        );
+
+        let config = GraphvizConfig {
+            max_output_size: Some(1000),
+            ..GraphvizConfig::default()
+        };
+        // we stop reading rather than waiting for output that never ends
+        let error = call_graphviz("yes", &[], "", None, &config)
+            .await
+            .expect_err("Expect the output to be too large");
+        assert_eq!(
+            error.to_string(),
+            "`yes` output more than the 1000 byte max-output-size and was stopped"
+        );
+    }
+
     #[tokio::test]
     async fn file_events() {
         let code = r#"digraph Test { a -> b }"#;
 
         let block = GraphvizBlock {
             graph_name: "Name".into(),
+            id: None,
+            anchor: "graphviz-name".into(),
+            engine: "dot".into(),
+            formats: vec![SVG_FORMAT.into()],
+            attributes: BlockAttributes::default(),
             code: code.into(),
             chapter_name: "".into(),
             chapter_path: "test-output".into(),
+            output_dir: "".into(),
             index: 0,
+            line: 1,
+            source: None,
         };
 
         let config = GraphvizConfig::default();
-        let mut events = CLIGraphvizToFile::render_graphviz(block, &config)
+        let mut events = CLIGraphvizToFile
+            .render_graphviz(block, &config)
             .await
             .expect("Expect rendering to succeed")
             .into_iter();
+        assert_eq!(
+            events.next(),
+            Some(Event::InlineHtml(r#"<a id="graphviz-name"></a>"#.into()))
+        );
         let next = events.next();
         assert!(
             matches!(next, Some(Event::Start(Tag::Image { .. }))),
             "Expected Image got {next:#?}"
         );
+        assert_eq!(events.next(), Some(Event::Text("Name".into())));
         let next = events.next();
         assert!(
             matches!(next, Some(Event::End(TagEnd::Image))),
@@ -194,26 +864,71 @@ mod test {
         assert_eq!(events.next(), None);
     }
 
+    #[tokio::test]
+    async fn file_events_with_chapter_links() {
+        let mut block = output_block(BlockAttributes::default());
+        block.code = r#"digraph Test { a [URL="../other/page.md"] }"#.into();
+        block.chapter_name = "Links".into();
+        block.chapter_path = "test-output".into();
+        block.output_dir = "graphviz-out".into();
+        let output_path = block.output_path_for(SVG_FORMAT);
+
+        let config = GraphvizConfig::default();
+        let mut events = CLIGraphvizToFile
+            .render_graphviz(block, &config)
+            .await
+            .expect("Expect rendering to succeed")
+            .into_iter();
+        assert_eq!(
+            events.next(),
+            Some(Event::InlineHtml(
+                r#"<object data="graphviz-out/links_name_0.generated.svg" type="image/svg+xml" title="Name" role="img" aria-label="Name" class="mdbook-graphviz-output" id="graphviz-name"></object>"#.into()
+            ))
+        );
+        assert_eq!(events.next(), Some(Event::Text("\n\n".into())));
+        assert_eq!(events.next(), None);
+
+        let svg = std::fs::read_to_string(output_path).unwrap();
+        assert!(
+            svg.contains(r#"xlink:href="../../other/page.html"#),
+            "{svg}"
+        );
+        assert!(svg.contains(r#"target="_top""#), "{svg}");
+    }
+
     #[tokio::test]
     async fn file_events_with_link() {
         let code = r#"digraph Test { a -> b }"#;
 
         let block = GraphvizBlock {
             graph_name: "Name".into(),
+            id: None,
+            anchor: "graphviz-name".into(),
+            engine: "dot".into(),
+            formats: vec![SVG_FORMAT.into()],
+            attributes: BlockAttributes::default(),
             code: code.into(),
             chapter_name: "".into(),
             chapter_path: "test-output".into(),
+            output_dir: "".into(),
             index: 0,
+            line: 1,
+            source: None,
         };
 
         let config = GraphvizConfig {
             link_to_file: true,
             ..GraphvizConfig::default()
         };
-        let mut events = CLIGraphvizToFile::render_graphviz(block, &config)
+        let mut events = CLIGraphvizToFile
+            .render_graphviz(block, &config)
             .await
             .expect("Expect rendering to succeed")
             .into_iter();
+        assert_eq!(
+            events.next(),
+            Some(Event::InlineHtml(r#"<a id="graphviz-name"></a>"#.into()))
+        );
         let next = events.next();
         assert!(
             matches!(next, Some(Event::Start(Tag::Link { .. }))),
@@ -224,6 +939,7 @@ mod test {
             matches!(next, Some(Event::Start(Tag::Image { .. }))),
             "Expected Image got {next:#?}"
         );
+        assert_eq!(events.next(), Some(Event::Text("Name".into())));
         let next = events.next();
         assert!(
             matches!(next, Some(Event::End(TagEnd::Image))),
@@ -237,4 +953,147 @@ mod test {
         assert_eq!(events.next(), Some(Event::Text("\n\n".into())));
         assert_eq!(events.next(), None);
     }
+
+    #[tokio::test]
+    async fn file_events_with_formats() {
+        let mut block = output_block(BlockAttributes::default());
+        block.graph_name = "Formats".into();
+        block.formats = vec![SVG_FORMAT.into(), "pdf".into()];
+        block.code = r#"digraph Test { a -> b }"#.into();
+        block.chapter_path = "test-output".into();
+
+        let config = GraphvizConfig::default();
+        let mut events = CLIGraphvizToFile
+            .render_graphviz(block, &config)
+            .await
+            .expect("Expect rendering to succeed")
+            .into_iter();
+        assert_eq!(
+            events.next(),
+            Some(Event::InlineHtml(r#"<a id="graphviz-name"></a>"#.into()))
+        );
+        let next = events.next();
+        assert!(
+            matches!(next, Some(Event::Start(Tag::Image { .. }))),
+            "Expected Image got {next:#?}"
+        );
+        assert_eq!(events.next(), Some(Event::Text("Formats".into())));
+        let next = events.next();
+        assert!(
+            matches!(next, Some(Event::End(TagEnd::Image))),
+            "Expected End Image got {next:#?}"
+        );
+        assert_eq!(events.next(), Some(Event::Text(" ".into())));
+        let next = events.next();
+        assert!(
+            matches!(next, Some(Event::Start(Tag::Link { ref dest_url, .. })) if dest_url.as_ref() == "chapter_formats_0.generated.pdf"),
+            "Expected Link got {next:#?}"
+        );
+        assert_eq!(events.next(), Some(Event::Text("PDF".into())));
+        assert_eq!(events.next(), Some(Event::End(TagEnd::Link)));
+        assert_eq!(events.next(), Some(Event::Text("\n\n".into())));
+        assert_eq!(events.next(), None);
+    }
+
+    fn output_block(attributes: BlockAttributes) -> GraphvizBlock {
+        GraphvizBlock {
+            graph_name: "Name".into(),
+            id: None,
+            anchor: "graphviz-name".into(),
+            engine: "dot".into(),
+            formats: vec![SVG_FORMAT.into()],
+            attributes,
+            code: "".into(),
+            chapter_name: "Chapter".into(),
+            chapter_path: "".into(),
+            output_dir: "".into(),
+            index: 0,
+            line: 1,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn output_width_and_class() {
+        let svg = r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<svg width="62pt" height="116pt" viewBox="0.00 0.00 62.00 116.00">
+<g id="graph0" class="graph"></g>
+</svg>"#;
+        let attributes = BlockAttributes {
+            width: Some(600),
+            class: Some(String::from("wide")),
+            ..BlockAttributes::default()
+        };
+
+        assert_eq!(
+            format_output(
+                svg.to_string(),
+                &output_block(attributes),
+                &GraphvizConfig::default()
+            )
+            .unwrap(),
+            r#"<div class="mdbook-graphviz-output wide" id="graphviz-name"><svg width="600" viewBox="0.00 0.00 62.00 116.00" role="img" aria-label="Name"><g id="graphviz_chapter_0_graph0" class="graph"></g></svg></div>"#
+        );
+    }
+
+    #[test]
+    fn output_theme_colors() {
+        let svg = r##"<svg width="62pt" height="116pt" viewBox="0.00 0.00 62.00 116.00">
+<polygon fill="white" stroke="none" points="-4,4 -4,-112 58,-112 58,4 -4,4"/>
+<ellipse fill="none" stroke="black" cx="27" cy="-90" rx="27" ry="18"/>
+<text text-anchor="middle" x="27" y="-86.3">a</text>
+<text text-anchor="middle" x="27" y="-14.3" fill="red">b</text>
+<polygon fill="#000000" stroke="black" style="opacity:0.5" points="30.5,-36.1 27,-26.1 23.5,-36.1 30.5,-36.1"/>
+</svg>"##;
+        let config = GraphvizConfig {
+            theme_aware: true,
+            ..GraphvizConfig::default()
+        };
+
+        assert_eq!(
+            format_output(
+                svg.to_string(),
+                &output_block(BlockAttributes::default()),
+                &config
+            )
+            .unwrap(),
+            concat!(
+                r#"<div class="mdbook-graphviz-output" id="graphviz-name"><svg width="62pt" height="116pt" viewBox="0.00 0.00 62.00 116.00" role="img" aria-label="Name">"#,
+                r#"<polygon stroke="none" points="-4,4 -4,-112 58,-112 58,4 -4,4" style="fill:var(--mdbook-graphviz-bg, white)"/>"#,
+                r#"<ellipse fill="none" cx="27" cy="-90" rx="27" ry="18" style="stroke:var(--mdbook-graphviz-fg, black)"/>"#,
+                r#"<text text-anchor="middle" x="27" y="-86.3" style="fill:var(--mdbook-graphviz-fg, black)">a</text>"#,
+                r#"<text text-anchor="middle" x="27" y="-14.3" fill="red">b</text>"#,
+                r#"<polygon style="fill:var(--mdbook-graphviz-fg, #000000);stroke:var(--mdbook-graphviz-fg, black);opacity:0.5" points="30.5,-36.1 27,-26.1 23.5,-36.1 30.5,-36.1"/>"#,
+                r#"</svg></div>"#
+            )
+        );
+    }
+
+    #[test]
+    fn output_prefixed_ids() {
+        let svg = r##"<svg width="62pt" height="116pt" viewBox="0.00 0.00 62.00 116.00">
+<defs><linearGradient id="l_0"></linearGradient></defs>
+<g id="graph0" class="graph">
+<g id="node1" class="node"><ellipse fill="url(#l_0)" cx="27" cy="-90" rx="27" ry="18"/></g>
+<g id="a_edge1"><a xlink:href="#node1" xlink:title="a&#45;&gt;b"></a><a href="https://example.com/#top"></a></g>
+</g>
+</svg>"##;
+
+        assert_eq!(
+            format_output(
+                svg.to_string(),
+                &output_block(BlockAttributes::default()),
+                &GraphvizConfig::default()
+            )
+            .unwrap(),
+            concat!(
+                r#"<div class="mdbook-graphviz-output" id="graphviz-name"><svg width="62pt" height="116pt" viewBox="0.00 0.00 62.00 116.00" role="img" aria-label="Name">"#,
+                r#"<defs><linearGradient id="graphviz_chapter_0_l_0"></linearGradient></defs>"#,
+                r#"<g id="graphviz_chapter_0_graph0" class="graph">"#,
+                r#"<g id="graphviz_chapter_0_node1" class="node"><ellipse fill="url(#graphviz_chapter_0_l_0)" cx="27" cy="-90" rx="27" ry="18"/></g>"#,
+                r##"<g id="graphviz_chapter_0_a_edge1"><a xlink:href="#graphviz_chapter_0_node1" xlink:title="a&#45;&gt;b"></a><a href="https://example.com/#top"></a></g>"##,
+                r#"</g></svg></div>"#
+            )
+        );
+    }
 }
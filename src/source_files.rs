@@ -0,0 +1,160 @@
+use std::path::{Path, PathBuf};
+
+use futures::future;
+use mdbook_preprocessor::errors::{Error, Result};
+use tokio::fs;
+
+use crate::config::{GraphvizConfig, OnError, format_extension};
+use crate::preprocessor::write_if_changed;
+use crate::renderer::{render_output, with_max_concurrency};
+
+/// The extensions of the Graphviz files we render from the book source
+static SOURCE_EXTENSIONS: &[&str] = &["dot", "gv"];
+
+/// Render every Graphviz file in our book source to images next to it, like `arch.dot.svg`,
+/// returning the files we generated
+pub async fn render_source_files(src_dir: &Path, config: &GraphvizConfig) -> Result<Vec<PathBuf>> {
+    let mut source_files = vec![];
+    find_source_files(src_dir, &mut source_files)?;
+    source_files.sort();
+
+    let rendered = with_max_concurrency(
+        config.concurrency_limit(),
+        future::join_all(
+            source_files
+                .iter()
+                .map(|source_file| render_source_file(source_file, config)),
+        ),
+    )
+    .await;
+
+    let mut generated_files = vec![];
+    for (source_file, result) in source_files.iter().zip(rendered) {
+        let error = match result {
+            Ok(output_files) => {
+                generated_files.extend(output_files);
+                continue;
+            }
+            Err(error) => error.context(format!("Failed to render `{}`", source_file.display())),
+        };
+        if config.on_error == OnError::Fail {
+            return Err(error);
+        }
+        eprintln!("Warning: {error:#}");
+    }
+
+    Ok(generated_files)
+}
+
+async fn render_source_file(source_file: &Path, config: &GraphvizConfig) -> Result<Vec<PathBuf>> {
+    let code = fs::read_to_string(source_file).await?;
+
+    let mut output_files = vec![];
+    for format in &config.formats {
+        let output = render_output(&config.engine, format, &code, config.timeout(), config).await?;
+        let output_file = output_path(source_file, format);
+        write_if_changed(&output_file, output)?;
+        output_files.push(output_file);
+    }
+
+    Ok(output_files)
+}
+
+/// Our output sits next to our source with the format's extension added, like `arch.dot.svg`
+fn output_path(source_file: &Path, format: &str) -> PathBuf {
+    let mut file_name = source_file.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(format_extension(format));
+
+    source_file.with_file_name(file_name)
+}
+
+fn find_source_files(dir: &Path, source_files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        Error::new(e).context(format!("Couldn't read the directory `{}`", dir.display()))
+    })?;
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_source_files(&path, source_files)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| SOURCE_EXTENSIONS.iter().any(|e| extension == *e))
+        {
+            source_files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn output_paths() {
+        assert_eq!(
+            output_path(Path::new("src/diagrams/arch.dot"), "svg"),
+            PathBuf::from("src/diagrams/arch.dot.svg")
+        );
+        assert_eq!(
+            output_path(Path::new("src/flow.gv"), "png:cairo"),
+            PathBuf::from("src/flow.gv.png")
+        );
+    }
+
+    #[test]
+    fn find_files() {
+        let dir = PathBuf::from("test-output/source-files");
+        std::fs::create_dir_all(dir.join("diagrams")).unwrap();
+        for file in [
+            "diagrams/arch.dot",
+            "flow.gv",
+            "chapter.md",
+            "diagrams/arch.dot.svg",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let mut source_files = vec![];
+        find_source_files(&dir, &mut source_files).unwrap();
+        source_files.sort();
+
+        assert_eq!(
+            source_files,
+            vec![dir.join("diagrams/arch.dot"), dir.join("flow.gv")]
+        );
+    }
+
+    #[tokio::test]
+    async fn render_files() {
+        let dir = PathBuf::from("test-output/source-files-render");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("arch.dot"), "digraph { a -> b }").unwrap();
+
+        let generated_files = render_source_files(&dir, &GraphvizConfig::default())
+            .await
+            .expect("Expect rendering to succeed");
+
+        assert_eq!(generated_files, vec![dir.join("arch.dot.svg")]);
+        assert!(dir.join("arch.dot.svg").exists());
+
+        // an unchanged image is left alone so that `mdbook serve` doesn't rebuild again
+        let modified = std::fs::metadata(dir.join("arch.dot.svg"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        render_source_files(&dir, &GraphvizConfig::default())
+            .await
+            .expect("Expect rendering to succeed");
+        assert_eq!(
+            std::fs::metadata(dir.join("arch.dot.svg"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use std::mem;
+use std::str;
+
+use mdbook_preprocessor::errors::Result;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+
+static THEME_FOREGROUND: &str = "--mdbook-graphviz-fg";
+static THEME_BACKGROUND: &str = "--mdbook-graphviz-bg";
+
+/// How to rewrite an SVG from Graphviz for inlining into a chapter
+#[derive(Debug, Default)]
+pub struct SvgOptions<'a> {
+    /// The width to scale our diagram to, in pixels
+    pub width: Option<u32>,
+    /// Prepended to every id, along with all of the references to them
+    pub id_prefix: &'a str,
+    /// Draw with CSS custom properties instead of black and white
+    pub theme_aware: bool,
+    /// Set for SVGs embedded from a file rather than inlined, the path from that file back to our
+    /// chapter. Relative links are rebased onto it and open in the page instead of the embed.
+    pub link_base: Option<&'a str>,
+    /// Our accessible name, for readers who can't see the diagram
+    pub label: Option<&'a str>,
+    /// A longer accessible description, added as a `<desc>`
+    pub description: Option<&'a str>,
+}
+
+/// Rewrite an SVG from Graphviz so that it can be inlined into a chapter. The XML prolog and
+/// comments are dropped along with the whitespace between elements, while the whitespace inside
+/// `<text>` elements is kept. Newlines are escaped since a blank line would end our HTML block.
+/// The root element is given an image role along with our accessible name and description.
+pub fn rewrite_svg(svg: &str, options: &SvgOptions) -> Result<String> {
+    let mut reader = Reader::from_str(svg);
+    let mut writer = Writer::new(Vec::with_capacity(svg.len()));
+    let mut is_root = true;
+    // whitespace is meaningful inside of `<text>`
+    let mut text_depth = 0usize;
+    let mut between_elements = true;
+
+    loop {
+        let event = reader.read_event()?;
+        let is_markup = !matches!(
+            event,
+            Event::Text(_) | Event::CData(_) | Event::GeneralRef(_)
+        );
+
+        match event {
+            Event::Eof => break,
+            Event::Decl(_) | Event::DocType(_) | Event::PI(_) | Event::Comment(_) => {}
+            Event::Start(element) => {
+                if element.name().as_ref() == b"text" {
+                    text_depth += 1;
+                }
+                let was_root = mem::take(&mut is_root);
+                let element = rewrite_element(&element, was_root, options)?;
+                writer.write_event(Event::Start(element))?;
+
+                if was_root && let Some(description) = options.description {
+                    let description = escape_newlines(&escape(description));
+                    writer.write_event(Event::Start(BytesStart::new("desc")))?;
+                    writer.write_event(Event::Text(BytesText::from_escaped(description)))?;
+                    writer.write_event(Event::End(BytesEnd::new("desc")))?;
+                }
+            }
+            Event::Empty(element) => {
+                let element = rewrite_element(&element, mem::take(&mut is_root), options)?;
+                writer.write_event(Event::Empty(element))?;
+            }
+            Event::End(element) => {
+                if element.name().as_ref() == b"text" {
+                    text_depth = text_depth.saturating_sub(1);
+                }
+                writer.write_event(Event::End(element))?;
+            }
+            Event::Text(text) => {
+                let content = str::from_utf8(&text)?;
+                if text_depth == 0 && between_elements && content.trim().is_empty() {
+                    continue;
+                }
+                let content = escape_newlines(content);
+                writer.write_event(Event::Text(BytesText::from_escaped(content)))?;
+            }
+            event => writer.write_event(event)?,
+        }
+
+        between_elements = is_markup;
+    }
+
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+fn rewrite_element(
+    element: &BytesStart,
+    is_root: bool,
+    options: &SvgOptions,
+) -> Result<BytesStart<'static>> {
+    let name = str::from_utf8(element.name().as_ref())?.to_string();
+
+    let mut attributes = Vec::new();
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        attributes.push((
+            str::from_utf8(attribute.key.as_ref())?.to_string(),
+            str::from_utf8(&attribute.value)?.to_string(),
+        ));
+    }
+
+    // scale to our width, the viewBox keeps our aspect ratio
+    if is_root && let Some(width) = options.width {
+        attributes.retain(|(key, _)| key != "height");
+        match attributes.iter_mut().find(|(key, _)| key == "width") {
+            Some((_, value)) => *value = width.to_string(),
+            None => attributes.insert(0, (String::from("width"), width.to_string())),
+        }
+    }
+
+    if is_root && let Some(label) = options.label {
+        if !attributes.iter().any(|(key, _)| key == "role") {
+            attributes.push((String::from("role"), String::from("img")));
+        }
+        attributes.retain(|(key, _)| key != "aria-label");
+        attributes.push((String::from("aria-label"), escape(label).into_owned()));
+    }
+
+    if name == "a" {
+        rewrite_links(&mut attributes, options.link_base);
+    }
+
+    if !options.id_prefix.is_empty() {
+        prefix_ids(&mut attributes, options.id_prefix);
+    }
+
+    if options.theme_aware {
+        theme_colors(&name, &mut attributes);
+    }
+
+    let mut rewritten = BytesStart::new(name);
+    for (key, value) in &attributes {
+        let value = escape_newlines(value);
+        rewritten.push_attribute((key.as_bytes(), value.as_bytes()));
+    }
+
+    Ok(rewritten)
+}
+
+/// Whether our SVG has any links that might need rewriting
+pub fn has_links(svg: &str) -> bool {
+    svg.contains("<a ")
+}
+
+/// The path of a relative link to a Markdown file, like `../design/storage.md#layout`
+pub fn markdown_link_path(href: &str) -> Option<&str> {
+    let path = relative_link_path(href)?;
+
+    path.ends_with(".md").then_some(path)
+}
+
+/// The path part of a link relative to our chapter, without any query or fragment
+fn relative_link_path(href: &str) -> Option<&str> {
+    let path = &href[..href.find(['?', '#']).unwrap_or(href.len())];
+    // skip absolute links and anything with a scheme like `https:` or `mailto:`
+    let has_scheme = path
+        .find(':')
+        .is_some_and(|colon| !path[..colon].contains('/'));
+    if path.is_empty() || path.starts_with('/') || has_scheme {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Point links to chapters at their HTML page, rebasing relative links if we're embedded
+fn rewrite_links(attributes: &mut Vec<(String, String)>, link_base: Option<&str>) {
+    for (key, value) in attributes.iter_mut() {
+        if key != "href" && key != "xlink:href" {
+            continue;
+        }
+        let Some(path) = relative_link_path(value) else {
+            continue;
+        };
+
+        let rest = &value[path.len()..];
+        let path = match path.strip_suffix(".md") {
+            Some(stem) => format!("{stem}.html"),
+            None => path.to_string(),
+        };
+        *value = format!("{}{path}{rest}", link_base.unwrap_or(""));
+    }
+
+    // links in an embedded SVG would otherwise open inside of the embed
+    if link_base.is_some() && !attributes.iter().any(|(key, _)| key == "target") {
+        attributes.push((String::from("target"), String::from("_top")));
+    }
+}
+
+/// Prefix our ids along with the references to them from links and paint servers
+fn prefix_ids(attributes: &mut [(String, String)], id_prefix: &str) {
+    for (key, value) in attributes {
+        if key == "id" {
+            value.insert_str(0, id_prefix);
+        } else if (key == "href" || key == "xlink:href") && value.starts_with('#') {
+            value.insert_str(1, id_prefix);
+        } else if value.contains("url(#") {
+            *value = value.replace("url(#", &format!("url(#{id_prefix}"));
+        }
+    }
+}
+
+/// Swap the black and white Graphviz draws with by default for CSS custom properties, so that our
+/// diagram follows the reader's mdBook theme. The original colors remain as the fallback.
+fn theme_colors(name: &str, attributes: &mut Vec<(String, String)>) {
+    let mut styles = Vec::new();
+    let mut has_fill = false;
+    attributes.retain(|(key, value)| {
+        if key != "fill" && key != "stroke" {
+            return true;
+        }
+        has_fill |= key == "fill";
+
+        match theme_variable(value) {
+            Some(variable) => {
+                styles.push(format!("{key}:var({variable}, {value})"));
+                false
+            }
+            None => true,
+        }
+    });
+    // text is black unless Graphviz was given a font color
+    if name == "text" && !has_fill {
+        styles.push(format!("fill:var({THEME_FOREGROUND}, black)"));
+    }
+    if styles.is_empty() {
+        return;
+    }
+
+    let styles = styles.join(";");
+    match attributes.iter_mut().find(|(key, _)| key == "style") {
+        Some((_, value)) => *value = format!("{styles};{value}"),
+        None => attributes.push((String::from("style"), styles)),
+    }
+}
+
+fn theme_variable(color: &str) -> Option<&'static str> {
+    match color.to_ascii_lowercase().as_str() {
+        "black" | "#000" | "#000000" => Some(THEME_FOREGROUND),
+        "white" | "#fff" | "#ffffff" => Some(THEME_BACKGROUND),
+        _ => None,
+    }
+}
+
+fn escape_newlines(content: &str) -> String {
+    content.replace('\r', "").replace('\n', "&#10;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prolog_and_whitespace() {
+        let svg = r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN"
+ "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
+<!-- Generated by graphviz version 12.2.1 -->
+<svg width="62pt" height="116pt" viewBox="0.00 0.00 62.00 116.00">
+<g id="graph0" class="graph">
+<title>Test</title>
+<!-- a -->
+<text text-anchor="middle" x="27" y="-86.3">a  &amp;  b</text>
+</g>
+</svg>
+"#;
+
+        assert_eq!(
+            rewrite_svg(svg, &SvgOptions::default()).unwrap(),
+            concat!(
+                r#"<svg width="62pt" height="116pt" viewBox="0.00 0.00 62.00 116.00">"#,
+                r#"<g id="graph0" class="graph"><title>Test</title>"#,
+                r#"<text text-anchor="middle" x="27" y="-86.3">a  &amp;  b</text>"#,
+                r#"</g></svg>"#
+            )
+        );
+    }
+
+    #[test]
+    fn text_whitespace() {
+        let svg = "<svg><text xml:space=\"preserve\"> </text><text>first\nsecond</text>\n\n<pre>&lt; &gt;</pre></svg>";
+
+        assert_eq!(
+            rewrite_svg(svg, &SvgOptions::default()).unwrap(),
+            "<svg><text xml:space=\"preserve\"> </text><text>first&#10;second</text><pre>&lt; &gt;</pre></svg>"
+        );
+    }
+
+    #[test]
+    fn attribute_newlines() {
+        let svg = "<svg><a xlink:title=\"first\nsecond\"></a></svg>";
+
+        assert_eq!(
+            rewrite_svg(svg, &SvgOptions::default()).unwrap(),
+            "<svg><a xlink:title=\"first&#10;second\"></a></svg>"
+        );
+    }
+
+    #[test]
+    fn chapter_links() {
+        let svg = concat!(
+            r##"<svg><a xlink:href="../design/storage.md#layout"></a><a href="other.md"></a>"##,
+            r##"<a xlink:href="images/big.png"></a><a xlink:href="https://example.com/a.md"></a>"##,
+            r##"<a xlink:href="/absolute.md"></a><a xlink:href="#node1"></a></svg>"##
+        );
+
+        assert_eq!(
+            rewrite_svg(svg, &SvgOptions::default()).unwrap(),
+            concat!(
+                r##"<svg><a xlink:href="../design/storage.html#layout"></a><a href="other.html"></a>"##,
+                r##"<a xlink:href="images/big.png"></a><a xlink:href="https://example.com/a.md"></a>"##,
+                r##"<a xlink:href="/absolute.md"></a><a xlink:href="#node1"></a></svg>"##
+            )
+        );
+    }
+
+    #[test]
+    fn embedded_links() {
+        let svg = r##"<svg><a xlink:href="other.md"></a><a xlink:href="images/big.png" target="_blank"></a></svg>"##;
+        let options = SvgOptions {
+            link_base: Some("../../guide/"),
+            ..SvgOptions::default()
+        };
+
+        assert_eq!(
+            rewrite_svg(svg, &options).unwrap(),
+            concat!(
+                r##"<svg><a xlink:href="../../guide/other.html" target="_top"></a>"##,
+                r##"<a xlink:href="../../guide/images/big.png" target="_blank"></a></svg>"##
+            )
+        );
+    }
+
+    #[test]
+    fn markdown_links() {
+        assert_eq!(
+            markdown_link_path("../design/storage.md#layout"),
+            Some("../design/storage.md")
+        );
+        assert_eq!(markdown_link_path("storage.html"), None);
+        assert_eq!(markdown_link_path("https://example.com/a.md"), None);
+        assert_eq!(markdown_link_path("#node1"), None);
+    }
+
+    #[test]
+    fn accessibility() {
+        let svg = r#"<svg width="62pt" height="116pt"><g id="graph0"><title>G</title></g></svg>"#;
+        let options = SvgOptions {
+            label: Some("Requests & storage"),
+            description: Some("Every request\nis <stored>"),
+            ..SvgOptions::default()
+        };
+
+        assert_eq!(
+            rewrite_svg(svg, &options).unwrap(),
+            concat!(
+                r#"<svg width="62pt" height="116pt" role="img" aria-label="Requests &amp; storage">"#,
+                r#"<desc>Every request&#10;is &lt;stored&gt;</desc>"#,
+                r#"<g id="graph0"><title>G</title></g></svg>"#
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_svg() {
+        assert!(rewrite_svg("<svg><g></svg>", &SvgOptions::default()).is_err());
+    }
+}